@@ -0,0 +1,280 @@
+use measurement::Measurement;
+use state::SensorState;
+
+use chrono::{DateTime, Duration, Utc};
+
+pub const US_AQI: &str = "us_aqi";
+pub const CAQI: &str = "caqi";
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Index {
+    pub value: u32,
+    pub category: String,
+    pub colour: String,
+    pub pollutant: String,
+    pub nowcast: bool,
+}
+
+struct Category {
+    name: &'static str,
+    colour: &'static str,
+    // Upper bound of the index for this category; the last category is unbounded
+    max: u32,
+}
+
+// (concentration low, concentration high, index low, index high)
+type Breakpoint = (f32, f32, f32, f32);
+
+// US EPA AQI breakpoints for PM 2.5 as revised in 2024 and for PM 10, in µg/m³
+const US_AQI_PM2: &[Breakpoint] = &[
+    (0.0, 9.0, 0.0, 50.0),
+    (9.1, 35.4, 51.0, 100.0),
+    (35.5, 55.4, 101.0, 150.0),
+    (55.5, 125.4, 151.0, 200.0),
+    (125.5, 225.4, 201.0, 300.0),
+    (225.5, 325.4, 301.0, 500.0),
+];
+
+const US_AQI_PM10: &[Breakpoint] = &[
+    (0.0, 54.0, 0.0, 50.0),
+    (55.0, 154.0, 51.0, 100.0),
+    (155.0, 254.0, 101.0, 150.0),
+    (255.0, 354.0, 151.0, 200.0),
+    (355.0, 424.0, 201.0, 300.0),
+    (425.0, 504.0, 301.0, 400.0),
+    (505.0, 604.0, 401.0, 500.0),
+];
+
+const US_AQI_CATEGORIES: &[Category] = &[
+    Category { name: "Good", colour: "#00E400", max: 50 },
+    Category { name: "Moderate", colour: "#FFFF00", max: 100 },
+    Category { name: "Unhealthy for Sensitive Groups", colour: "#FF7E00", max: 150 },
+    Category { name: "Unhealthy", colour: "#FF0000", max: 200 },
+    Category { name: "Very Unhealthy", colour: "#8F3F97", max: 300 },
+    Category { name: "Hazardous", colour: "#7E0023", max: 500 },
+];
+
+// Hourly CAQI grid; concentrations above the grid are extrapolated from its last band
+const CAQI_PM2: &[Breakpoint] = &[
+    (0.0, 15.0, 0.0, 25.0),
+    (15.0, 30.0, 25.0, 50.0),
+    (30.0, 55.0, 50.0, 75.0),
+    (55.0, 110.0, 75.0, 100.0),
+];
+
+const CAQI_PM10: &[Breakpoint] = &[
+    (0.0, 25.0, 0.0, 25.0),
+    (25.0, 50.0, 25.0, 50.0),
+    (50.0, 90.0, 50.0, 75.0),
+    (90.0, 180.0, 75.0, 100.0),
+];
+
+const CAQI_CATEGORIES: &[Category] = &[
+    Category { name: "Very Low", colour: "#79BC6A", max: 25 },
+    Category { name: "Low", colour: "#BBCF4C", max: 50 },
+    Category { name: "Medium", colour: "#EEC20B", max: 75 },
+    Category { name: "High", colour: "#F29305", max: 100 },
+    Category { name: "Very High", colour: "#E8416F", max: ::std::u32::MAX },
+];
+
+// The US EPA AQI uses the NowCast of the last 12 hours if the sensor state holds enough readings
+pub fn us_aqi(measurement: &Measurement, sensor_state: Option<&SensorState>) -> Option<Index> {
+    let timestamp = measurement.timestamp.unwrap_or(measurement.fetched_at);
+    let concentration = |pollutant: &str| {
+        let current = measurement.data_values.iter().filter_map(|v| v.select(pollutant)).next()?;
+        let nowcast = sensor_state.and_then(|s| {
+            let samples = s.samples(pollutant, timestamp - Duration::hours(12), timestamp)
+                .into_iter()
+                .map(|s| (s.timestamp, s.value))
+                .collect::<Vec<_>>();
+            nowcast(&samples, timestamp, current)
+        });
+        Some(nowcast.map(|c| (c, true)).unwrap_or((current, false)))
+    };
+
+    let pm2 = concentration("pm2").map(|(c, nowcast)| {
+        // PM 2.5 is truncated to one decimal and PM 10 to an integer before looking up the breakpoints
+        let c = (c * 10.0).floor() / 10.0;
+        (sub_index(c, US_AQI_PM2, false), "pm2", nowcast)
+    });
+    let pm10 = concentration("pm10").map(|(c, nowcast)| (sub_index(c.floor(), US_AQI_PM10, false), "pm10", nowcast));
+
+    let (value, pollutant, nowcast) = max_sub_index(pm2, pm10)?;
+    Some(index(value, pollutant, nowcast, US_AQI_CATEGORIES))
+}
+
+pub fn caqi(measurement: &Measurement) -> Option<Index> {
+    let pm2 = measurement.data_values.iter().filter_map(|v| v.pm2()).next()
+        .map(|c| (sub_index(c, CAQI_PM2, true), "pm2", false));
+    let pm10 = measurement.data_values.iter().filter_map(|v| v.pm10()).next()
+        .map(|c| (sub_index(c, CAQI_PM10, true), "pm10", false));
+
+    let (value, pollutant, nowcast) = max_sub_index(pm2, pm10)?;
+    Some(index(value, pollutant, nowcast, CAQI_CATEGORIES))
+}
+
+// NowCast weighs the hourly means of the last 12 hours; it requires at least two of the last three hours
+pub fn nowcast(samples: &[(DateTime<Utc>, f32)], timestamp: DateTime<Utc>, current: f32) -> Option<f32> {
+    let mut sums = [0f32; 12];
+    let mut counts = [0u32; 12];
+    sums[0] = current;
+    counts[0] = 1;
+    for &(t, value) in samples {
+        let hour = timestamp.signed_duration_since(t).num_hours();
+        if hour >= 0 && hour < 12 {
+            sums[hour as usize] += value;
+            counts[hour as usize] += 1;
+        }
+    }
+    if counts[..3].iter().filter(|c| **c > 0).count() < 2 {
+        return None;
+    }
+
+    let hourly = (0..12)
+        .filter(|i| counts[*i] > 0)
+        .map(|i| (i as i32, sums[i] / counts[i] as f32))
+        .collect::<Vec<_>>();
+    let min = hourly.iter().map(|&(_, c)| c).fold(::std::f32::MAX, f32::min);
+    let max = hourly.iter().map(|&(_, c)| c).fold(0.0, f32::max);
+    let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+    let (sum, weights) = hourly.iter().fold((0.0, 0.0), |(sum, weights), &(i, c)| {
+        let w = weight.powi(i);
+        (sum + w * c, weights + w)
+    });
+
+    Some(sum / weights)
+}
+
+// Returns the index above which a value belongs to the given category or a worse one
+pub fn category_limit(scale: &str, category: &str) -> Option<f32> {
+    let categories = match &scale.to_lowercase()[..] {
+        US_AQI => US_AQI_CATEGORIES,
+        CAQI => CAQI_CATEGORIES,
+        _ => return None,
+    };
+    let pos = categories.iter().position(|c| c.name.to_lowercase() == category.to_lowercase())?;
+    if pos == 0 {
+        return None;
+    }
+
+    Some(categories[pos - 1].max as f32)
+}
+
+pub fn category_names(scale: &str) -> Vec<&'static str> {
+    let categories = match &scale.to_lowercase()[..] {
+        US_AQI => US_AQI_CATEGORIES,
+        CAQI => CAQI_CATEGORIES,
+        _ => return Vec::new(),
+    };
+
+    categories.iter().map(|c| c.name).collect()
+}
+
+fn sub_index(c: f32, breakpoints: &[Breakpoint], extrapolate: bool) -> u32 {
+    let c = c.max(0.0);
+    // Concentrations between two bands belong to the upper one
+    let bp = breakpoints.iter().find(|bp| c <= bp.1);
+    let (c_lo, c_hi, i_lo, i_hi) = match bp {
+        Some(bp) => *bp,
+        // Unwrap is safe because the breakpoint tables are not empty
+        None if extrapolate => *breakpoints.last().unwrap(),
+        None => return breakpoints.last().unwrap().3 as u32,
+    };
+    let c = c.max(c_lo);
+
+    ((i_hi - i_lo) / (c_hi - c_lo) * (c - c_lo) + i_lo).round() as u32
+}
+
+fn max_sub_index<'a>(pm2: Option<(u32, &'a str, bool)>, pm10: Option<(u32, &'a str, bool)>) -> Option<(u32, &'a str, bool)> {
+    match (pm2, pm10) {
+        (Some(pm2), Some(pm10)) => Some(if pm10.0 > pm2.0 { pm10 } else { pm2 }),
+        (pm2, pm10) => pm2.or(pm10),
+    }
+}
+
+fn index(value: u32, pollutant: &str, nowcast: bool, categories: &[Category]) -> Index {
+    // Unwrap is safe because the last category is reached at the latest
+    let category = categories.iter().find(|c| value <= c.max).unwrap_or_else(|| categories.last().unwrap());
+
+    Index {
+        value: value,
+        category: category.name.to_string(),
+        colour: category.colour.to_string(),
+        pollutant: pollutant.to_string(),
+        nowcast: nowcast,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use measurement::Value;
+    use sensor::Sensor;
+
+    fn measurement(pm10: f32, pm2: f32) -> Measurement {
+        Measurement {
+            sensor: Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(pm10), Value::SDS_P2(pm2)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        }
+    }
+
+    #[test]
+    fn us_aqi_okay() -> () {
+        let res = us_aqi(&measurement(20.0, 60.4), None).unwrap();
+
+        assert_eq!(res.value, 154);
+        assert_eq!(res.category, "Unhealthy");
+        assert_eq!(res.colour, "#FF0000");
+        assert_eq!(res.pollutant, "pm2");
+        assert!(!res.nowcast);
+    }
+
+    #[test]
+    fn us_aqi_breakpoints() -> () {
+        assert_eq!(sub_index(9.0, US_AQI_PM2, false), 50);
+        assert_eq!(sub_index(35.4, US_AQI_PM2, false), 100);
+        assert_eq!(sub_index(500.0, US_AQI_PM2, false), 500);
+        assert_eq!(sub_index(154.0, US_AQI_PM10, false), 100);
+        assert_eq!(sub_index(155.0, US_AQI_PM10, false), 101);
+    }
+
+    #[test]
+    fn caqi_okay() -> () {
+        let res = caqi(&measurement(70.0, 10.0)).unwrap();
+
+        assert_eq!(res.value, 63);
+        assert_eq!(res.category, "Medium");
+        assert_eq!(res.pollutant, "pm10");
+        assert_eq!(sub_index(270.0, CAQI_PM10, true), 125);
+    }
+
+    #[test]
+    fn nowcast_okay() -> () {
+        let now = Utc::now();
+        let samples = vec![
+            (now - Duration::minutes(90), 20.0),
+            (now - Duration::minutes(150), 40.0),
+        ];
+
+        // Hourly means of 10, 20, and 40 result in a weight of 0.5
+        let res = nowcast(&samples, now, 10.0).unwrap();
+        assert!((res - (10.0 + 0.5 * 20.0 + 0.25 * 40.0) / 1.75).abs() < 0.001);
+
+        assert_eq!(nowcast(&[(now - Duration::hours(5), 20.0)], now, 10.0), None);
+    }
+
+    #[test]
+    fn category_limit_okay() -> () {
+        assert_eq!(category_limit("us_aqi", "unhealthy"), Some(150.0));
+        assert_eq!(category_limit("CAQI", "High"), Some(75.0));
+        assert_eq!(category_limit("us_aqi", "Good"), None);
+        assert_eq!(category_limit("us_aqi", "High"), None);
+    }
+}
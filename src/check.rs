@@ -1,45 +1,629 @@
+use aqi::{self, Index};
+use config::{parse_duration, DEFAULT_EXCEEDANCE_LIMIT};
 use measurement::{Measurement, Value};
+use sensor::Sensor;
+use state::{Exceedances, SensorState};
+
+use chrono::{DateTime, Datelike, Duration, Utc};
+use std::fmt;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Alert,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Alert => write!(f, "alert"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+// Particulates thresholds are either a plain limit or a limit for the mean over a time window
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Threshold {
+    Value(f32),
+    Mean { mean: String, above: f32 },
+}
+
+impl Threshold {
+    pub fn above(&self) -> f32 {
+        match *self {
+            Threshold::Value(above) | Threshold::Mean { above, .. } => above,
+        }
+    }
+
+    pub fn mean(&self) -> Option<&str> {
+        match *self {
+            Threshold::Value(_) => None,
+            Threshold::Mean { ref mean, .. } => Some(mean),
+        }
+    }
+}
+
+// The κ-Köhler correction follows Crilley et al. (2018) and assumes a particle density of 1.65 g/cm³
+const KAPPA_KOEHLER_DENSITY: f32 = 1.65;
+
+// Optical sensors overestimate particulates at high relative humidity because the particles grow by absorbing water
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone, Copy)]
+#[serde(tag = "model")]
+pub enum HumidityCorrection {
+    KappaKoehler { kappa: f32 },
+}
+
+impl HumidityCorrection {
+    pub fn correct(&self, value: f32, humidity: f32) -> f32 {
+        match *self {
+            HumidityCorrection::KappaKoehler { kappa } => {
+                // The growth factor diverges towards saturation, so the relative humidity is capped at 99%
+                let rh = humidity.max(0.0).min(99.0) / 100.0;
+                value / (1.0 + kappa / KAPPA_KOEHLER_DENSITY * rh / (1.0 - rh))
+            }
+        }
+    }
+}
+
+// Readings outside of the physically plausible range of a value type indicate a malfunctioning sensor
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlausibleRange {
+    pub value: String,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl PlausibleRange {
+    pub fn check_value(&self, value: &Value) -> Option<String> {
+        let v = value.select(&self.value)?;
+        match (self.min, self.max) {
+            (Some(min), _) if v < min => Some(format!("{} {} below plausible minimum {}", value.name(), v, min)),
+            (_, Some(max)) if v > max => Some(format!("{} {} above plausible maximum {}", value.name(), v, max)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    pub value: String,
+    pub above: Option<f32>,
+    pub below: Option<f32>,
+    pub mean: Option<String>,
+    pub severity: Option<Severity>,
+    pub clear_margin: Option<f32>,
+    pub category: Option<String>,
+    pub rise: Option<f32>,
+    pub fall: Option<f32>,
+    pub within: Option<String>,
+}
+
+impl Rule {
+    pub fn new<T: Into<String>>(value: T, above: Option<f32>, below: Option<f32>) -> Rule {
+        Rule {
+            name: None,
+            value: value.into(),
+            above: above,
+            below: below,
+            mean: None,
+            severity: None,
+            clear_margin: None,
+            category: None,
+            rise: None,
+            fall: None,
+            within: None,
+        }
+    }
+
+    // Rules without an explicit severity raise an alert like the particulates thresholds
+    pub fn severity(&self) -> Severity {
+        self.severity.unwrap_or(Severity::Alert)
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.description())
+    }
+
+    // Names may be shared by several rules, so the state identifies rules by their name and definition
+    pub fn id(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("{} ({})", name, self.description()),
+            None => self.description(),
+        }
+    }
+
+    fn description(&self) -> String {
+        if let Some(ref category) = self.category {
+            return format!("{} {} or worse", self.value, category);
+        }
+        if let Some(ref within) = self.within {
+            return match (self.rise, self.fall) {
+                (Some(rise), Some(fall)) => format!("{} rise by {} or fall by {} within {}", self.value, rise, fall, within),
+                (Some(rise), None) => format!("{} rise by {} within {}", self.value, rise, within),
+                (None, Some(fall)) => format!("{} fall by {} within {}", self.value, fall, within),
+                (None, None) => format!("{} change within {}", self.value, within),
+            };
+        }
+        let value = match self.mean {
+            Some(ref mean) => format!("{} {} mean", self.value, mean),
+            None => self.value.clone(),
+        };
+        match (self.above, self.below) {
+            (Some(above), Some(below)) => format!("{} outside {} to {}", value, below, above),
+            (Some(above), None) => format!("{} above {}", value, above),
+            (None, Some(below)) => format!("{} below {}", value, below),
+            (None, None) => value,
+        }
+    }
+
+    // Time window of either the mean or the rate of change
+    pub fn window(&self) -> Option<Duration> {
+        self.mean.as_ref().or(self.within.as_ref()).and_then(|window| parse_duration(window))
+    }
+
+    pub fn is_rate(&self) -> bool {
+        self.within.is_some()
+    }
+
+    // Rate of change rules check the change within their time window against the rise and the negated fall
+    fn limits(&self) -> (Option<f32>, Option<f32>) {
+        if self.is_rate() {
+            (self.rise, self.fall.map(|fall| -fall))
+        } else {
+            (self.above, self.below)
+        }
+    }
+
+    pub fn select(&self, value: &Value) -> Option<f32> {
+        value.select(&self.value)
+    }
+
+    pub fn is_aqi(&self) -> bool {
+        let value = self.value.to_lowercase();
+        value == aqi::US_AQI || value == aqi::CAQI
+    }
+
+    // A violated rule only clears once the value is back within the violated limit by more than the clear margin
+    pub fn check(&self, value: &Value, violated: Option<Direction>) -> Option<Violation> {
+        let v = self.select(value)?;
+        self.check_value(value, v, violated)
+    }
+
+    // Checks an already selected value, e.g., the mean of the selected value over the rule's time window
+    pub fn check_value(&self, value: &Value, v: f32, violated: Option<Direction>) -> Option<Violation> {
+        let margin = |direction: Direction| if violated == Some(direction) { self.clear_margin.unwrap_or(0.0) } else { 0.0 };
+        let violation = |limit: f32, direction: Direction| Violation {
+            rule: self.name(),
+            rule_id: self.id(),
+            value: value.clone(),
+            measured: v,
+            limit: limit,
+            direction: direction,
+            severity: self.severity(),
+        };
+        match self.limits() {
+            (Some(above), _) if v > above - margin(Direction::Above) => Some(violation(above, Direction::Above)),
+            (_, Some(below)) if v < below + margin(Direction::Below) => Some(violation(below, Direction::Below)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Above => write!(f, "above"),
+            Direction::Below => write!(f, "below"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Violation {
+    pub rule: String,
+    #[serde(default)]
+    pub rule_id: String,
+    pub value: Value,
+    pub measured: f32,
+    pub limit: f32,
+    pub direction: Direction,
+    pub severity: Severity,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Mean {
+    pub value: String,
+    pub window: String,
+    pub mean: f32,
+    pub samples: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Rate {
+    pub value: String,
+    pub window: String,
+    pub delta: f32,
+    pub reference: f32,
+    pub since: DateTime<Utc>,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CheckedMeasurement {
     pub measurement: Measurement,
     pub has_violations: bool,
-    pub violations: Vec<Value>,
+    pub violations: Vec<Violation>,
+    pub means: Vec<Mean>,
+    pub rates: Vec<Rate>,
+    pub exceedances: Option<Exceedances>,
+    pub severity: Option<Severity>,
+    pub us_aqi: Option<Index>,
+    pub caqi: Option<Index>,
+    pub pm10: Option<f32>,
+    pub pm2: Option<f32>,
+    pub pm1: Option<f32>,
+    pub raw_pm10: Option<f32>,
+    pub raw_pm2: Option<f32>,
+    pub raw_pm1: Option<f32>,
+    pub raw_data_values: Vec<Value>,
+    pub humidity: Option<f32>,
+    pub humidity_corrected: bool,
+    pub humidity_suppressed: bool,
+    pub stale: bool,
+    pub suspect: bool,
+    pub suspect_reasons: Vec<String>,
+}
+
+impl CheckedMeasurement {
+    // Stuck values are detected on the raw particulates because corrections depend on the humidity
+    pub fn raw_particulates(&self) -> Vec<f32> {
+        [self.raw_pm10, self.raw_pm2, self.raw_pm1].iter().filter_map(|v| *v).collect()
+    }
 }
 
 pub fn check_measurement(measurement: Measurement) -> CheckedMeasurement {
-    let violations: Vec<_> = measurement
-        .data_values
-        .clone()
-        .into_iter()
-        .map(|value| match value {
-            // Unwraps are safe because they are checked during config parsing
-            Value::SDS_P1(v) if v > measurement.sensor.threshold_pm10.unwrap() => Some(value),
-            Value::SDS_P2(v) if v > measurement.sensor.threshold_pm2.unwrap() => Some(value),
-            _ => None,
+    check_measurement_with_state(measurement, None)
+}
+
+// The sensor state holds the rules violated by the previous measurement for the clear margins to apply
+// and the history of previous readings for the means
+pub fn check_measurement_with_state(measurement: Measurement, sensor_state: Option<&SensorState>) -> CheckedMeasurement {
+    let mut measurement = measurement;
+    let raw_pm10 = measurement.data_values.iter().filter_map(|v| v.pm10()).next();
+    let raw_pm2 = measurement.data_values.iter().filter_map(|v| v.pm2()).next();
+    let raw_pm1 = measurement.data_values.iter().filter_map(|v| v.pm1()).next();
+    let humidity = measurement.data_values.iter().filter_map(|v| v.humidity()).next();
+    let suspect_reasons = check_plausibility(&measurement, [raw_pm10, raw_pm2, raw_pm1], sensor_state);
+    let suspect = !suspect_reasons.is_empty();
+    let raw_data_values = measurement.data_values.clone();
+    // Corrected particulates replace the raw ones for all checks, means, and the history
+    let humidity_corrected = match (measurement.sensor.humidity_correction, humidity) {
+        (Some(correction), Some(humidity)) => {
+            measurement.data_values = correct_humidity(&measurement.data_values, correction, humidity);
+            true
+        }
+        _ => false,
+    };
+    let humidity_suppressed = match (measurement.sensor.suppress_above_humidity, humidity) {
+        (Some(max), Some(humidity)) => humidity > max,
+        _ => false,
+    };
+
+    let mut violations = Vec::new();
+    let mut means: Vec<Mean> = Vec::new();
+    let mut rates: Vec<Rate> = Vec::new();
+    let mut exceedances = None;
+    let us_aqi = aqi::us_aqi(&measurement, sensor_state);
+    let caqi = aqi::caqi(&measurement);
+    // Stale readings are not checked in order to not raise alarms for sensors that stopped sampling;
+    // suspect readings are not checked in order to not raise alarms for malfunctioning sensors
+    let stale = measurement.is_stale();
+    if !stale && !suspect {
+        let timestamp = measurement.timestamp.unwrap_or(measurement.fetched_at);
+        let rules = sensor_rules(&measurement.sensor);
+        for value in &measurement.data_values {
+            // Particulates are not checked at all if the relative humidity is too high for the sensor to be reliable
+            if humidity_suppressed && value.is_particulates() {
+                continue;
+            }
+            for rule in &rules {
+                let v = match rule.select(value) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let v = match (rule.mean.as_ref(), rule.within.as_ref(), rule.window()) {
+                    (Some(window_str), _, Some(window)) => {
+                        let mean = compute_mean(sensor_state, &rule.value, window_str, window, timestamp, v);
+                        let v = mean.mean;
+                        if !means.contains(&mean) {
+                            means.push(mean);
+                        }
+                        v
+                    }
+                    (_, Some(window_str), Some(window)) => {
+                        // Without previous readings within the time window, there is no change to check yet
+                        let rate = match compute_rate(sensor_state, &rule.value, window_str, window, timestamp, v) {
+                            Some(rate) => rate,
+                            None => continue,
+                        };
+                        let v = rate.delta;
+                        if !rates.contains(&rate) {
+                            rates.push(rate);
+                        }
+                        v
+                    }
+                    _ => v,
+                };
+                let violated = sensor_state.and_then(|s| s.violated_direction(&rule.id()));
+                if let Some(violation) = rule.check_value(value, v, violated) {
+                    violations.push(violation);
+                }
+            }
+        }
+        // AQI rules check the index; the violation refers to the particulates value dominating the index
+        for rule in rules.iter().filter(|r| r.is_aqi() && !humidity_suppressed) {
+            let index = if rule.value.to_lowercase() == aqi::US_AQI { us_aqi.as_ref() } else { caqi.as_ref() };
+            let index = match index {
+                Some(index) => index,
+                None => continue,
+            };
+            let value = measurement.data_values.iter().find(|v| v.select(&index.pollutant).is_some());
+            let violated = sensor_state.and_then(|s| s.violated_direction(&rule.id()));
+            if let Some(violation) = value.and_then(|value| rule.check_value(value, index.value as f32, violated)) {
+                violations.push(violation);
+            }
+        }
+        let limit = measurement.sensor.exceedance_limit.unwrap_or(DEFAULT_EXCEEDANCE_LIMIT);
+        exceedances = sensor_state.map(|s| s.exceedances(timestamp.year(), limit, timestamp.naive_utc().date()));
+        let pm10 = measurement.data_values.iter().find(|v| v.pm10().is_some());
+        if let (Some(e), Some(max_days), Some(value)) = (exceedances.as_ref(), measurement.sensor.max_exceedance_days, pm10) {
+            if e.days > max_days {
+                violations.push(Violation {
+                    rule: "max_exceedance_days".to_string(),
+                    rule_id: "max_exceedance_days".to_string(),
+                    value: value.clone(),
+                    measured: e.days as f32,
+                    limit: max_days as f32,
+                    direction: Direction::Above,
+                    severity: Severity::Alert,
+                });
+            }
+        }
+    }
+    let severity = violations.iter().map(|v| v.severity).max();
+    let pm10 = measurement.data_values.iter().filter_map(|v| v.pm10()).next();
+    let pm2 = measurement.data_values.iter().filter_map(|v| v.pm2()).next();
+    let pm1 = measurement.data_values.iter().filter_map(|v| v.pm1()).next();
+
+    CheckedMeasurement {
+        measurement: measurement,
+        has_violations: !violations.is_empty(),
+        violations: violations,
+        means: means,
+        rates: rates,
+        exceedances: exceedances,
+        severity: severity,
+        us_aqi: us_aqi,
+        caqi: caqi,
+        pm10: pm10,
+        pm2: pm2,
+        pm1: pm1,
+        raw_pm10: raw_pm10,
+        raw_pm2: raw_pm2,
+        raw_pm1: raw_pm1,
+        raw_data_values: raw_data_values,
+        humidity: humidity,
+        humidity_corrected: humidity_corrected,
+        humidity_suppressed: humidity_suppressed,
+        stale: stale,
+        suspect: suspect,
+        suspect_reasons: suspect_reasons,
+    }
+}
+
+// A reading is suspect if a value is out of its plausible range or if the particulates are stuck at the same values
+fn check_plausibility(measurement: &Measurement, raw_particulates: [Option<f32>; 3], sensor_state: Option<&SensorState>) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if let Some(ref ranges) = measurement.sensor.plausible_ranges {
+        for value in &measurement.data_values {
+            reasons.extend(ranges.iter().filter_map(|r| r.check_value(value)));
+        }
+    }
+    let particulates = raw_particulates.iter().filter_map(|v| *v).collect::<Vec<_>>();
+    if let (Some(readings), Some(state)) = (measurement.sensor.stuck_readings, sensor_state) {
+        let identical_readings = state.count_identical_readings(measurement, &particulates);
+        if identical_readings >= readings {
+            reasons.push(format!("particulates stuck at the same values for {} readings", identical_readings));
+        }
+    }
+
+    reasons
+}
+
+fn correct_humidity(data_values: &[Value], correction: HumidityCorrection, humidity: f32) -> Vec<Value> {
+    data_values
+        .iter()
+        .map(|v| match v.value() {
+            Some(value) if v.is_particulates() => v.with_value(correction.correct(value, humidity)),
+            _ => v.clone(),
+        })
+        .collect()
+}
+
+fn compute_mean(
+    sensor_state: Option<&SensorState>,
+    value: &str,
+    window_str: &str,
+    window: Duration,
+    timestamp: DateTime<Utc>,
+    current: f32,
+) -> Mean {
+    let value = value.to_lowercase();
+    let samples = sensor_state
+        .map(|s| s.samples(&value, timestamp - window, timestamp))
+        .unwrap_or_else(Vec::new);
+    let sum = samples.iter().fold(current, |sum, s| sum + s.value);
+    let count = samples.len() + 1;
+
+    Mean {
+        value: value,
+        window: window_str.to_string(),
+        mean: sum / count as f32,
+        samples: count,
+    }
+}
+
+// The change is relative to the oldest previous reading within the time window
+fn compute_rate(
+    sensor_state: Option<&SensorState>,
+    value: &str,
+    window_str: &str,
+    window: Duration,
+    timestamp: DateTime<Utc>,
+    current: f32,
+) -> Option<Rate> {
+    let value = value.to_lowercase();
+    let samples = sensor_state.map(|s| s.samples(&value, timestamp - window, timestamp))?;
+    let oldest = samples.iter().min_by_key(|s| s.timestamp)?;
+
+    Some(Rate {
+        value: value,
+        window: window_str.to_string(),
+        delta: current - oldest.value,
+        reference: oldest.value,
+        since: oldest.timestamp,
+    })
+}
+
+// The particulates thresholds are shorthands for the corresponding rules
+pub fn sensor_rules(sensor: &Sensor) -> Vec<Rule> {
+    let thresholds = [
+        ("threshold_pm10", "pm10", &sensor.threshold_pm10),
+        ("threshold_pm2", "pm2", &sensor.threshold_pm2),
+        ("threshold_pm1", "pm1", &sensor.threshold_pm1),
+    ];
+    let mut rules: Vec<_> = thresholds
+        .iter()
+        .filter_map(|&(name, value, threshold)| {
+            threshold.as_ref().map(|t| Rule {
+                name: Some(name.to_string()),
+                mean: t.mean().map(|m| m.to_string()),
+                ..Rule::new(value, Some(t.above()), None)
+            })
         })
-        .flat_map(|v| v)
         .collect();
+    if let Some(ref sensor_rules) = sensor.rules {
+        rules.extend(sensor_rules.iter().cloned());
+    }
+    for rule in &mut rules {
+        // The default clear margin is a concentration, which does not apply to changes within a time window
+        if !rule.is_rate() {
+            rule.clear_margin = rule.clear_margin.or(sensor.clear_margin);
+        }
+        // AQI categories are shorthands for the index at which the category starts
+        if let Some(ref category) = rule.category {
+            rule.above = rule.above.or_else(|| aqi::category_limit(&rule.value, category));
+        }
+    }
 
-    CheckedMeasurement { measurement: measurement, has_violations: !violations.is_empty(), violations: violations }
+    rules
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use measurement::{Measurement, Value};
-    use sensor::Sensor;
+    use state::{DailyMean, Sample};
 
     #[test]
     fn check_measurement_okay() -> () {
         let sensor = Sensor {
-            name: "A Sensor".to_string(),
-            id: "123456789".to_string(),
-            ui_uri: "http://localhost".to_string(),
-            data_uri: "http://localhost".to_string(),
-            threshold_pm10: Some(10.0),
-            threshold_pm2: Some(2.0),
-            notification_condition: None,
+            threshold_pm10: Some(Threshold::Value(10.0)),
+            threshold_pm2: Some(Threshold::Value(2.0)),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut data_values = Vec::new();
+        data_values.push(Value::SDS_P1(17.87f32));
+        data_values.push(Value::SDS_P2(3.17f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        let res = check_measurement(measurement);
+
+        assert_eq!(res.violations.len(), 2);
+        assert!(res.has_violations);
+
+    }
+
+    #[test]
+    fn check_measurement_pm1_okay() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Value(50.0)),
+            threshold_pm2: Some(Threshold::Value(50.0)),
+            threshold_pm1: Some(Threshold::Value(5.0)),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut data_values = Vec::new();
+        data_values.push(Value::PMS_P0(7.12f32));
+        data_values.push(Value::PMS_P1(17.87f32));
+        data_values.push(Value::PMS_P2(3.17f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2018-123B".to_string(),
+            data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        let res = check_measurement(measurement);
+
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].rule, "threshold_pm1");
+        assert_eq!(res.violations[0].value, Value::PMS_P0(7.12f32));
+        assert_eq!(res.pm10, Some(17.87f32));
+        assert_eq!(res.pm2, Some(3.17f32));
+        assert_eq!(res.pm1, Some(7.12f32));
+    }
+
+    #[test]
+    fn check_measurement_stale() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Value(10.0)),
+            threshold_pm2: Some(Threshold::Value(2.0)),
+            max_age: Some(300),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
         };
         let mut data_values = Vec::new();
         data_values.push(Value::SDS_P1(17.87f32));
@@ -48,12 +632,451 @@ mod test {
             sensor: sensor,
             software_version: "NRZ-2017-089".to_string(),
             data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: Some(3600),
+        };
+
+        let res = check_measurement(measurement);
+
+        assert!(res.stale);
+        assert!(res.violations.is_empty());
+        assert!(!res.has_violations);
+    }
+
+    #[test]
+    fn check_measurement_rules() -> () {
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            rules: Some(vec![
+                Rule {
+                    name: Some("frost".to_string()),
+                    ..Rule::new("temperature", None, Some(-5.0))
+                },
+                Rule::new("humidity", Some(90.0), Some(10.0)),
+                Rule::new("signal", None, Some(-80.0)),
+            ]),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut data_values = Vec::new();
+        data_values.push(Value::SDS_P1(17.87f32));
+        data_values.push(Value::TEMPERATURE(-7.0f32));
+        data_values.push(Value::HUMIDITY(95.0f32));
+        data_values.push(Value::SIGNAL(-73f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        let res = check_measurement(measurement);
+
+        assert_eq!(
+            res.violations,
+            vec![
+                Violation {
+                    rule: "frost".to_string(),
+                    rule_id: "frost (temperature below -5)".to_string(),
+                    value: Value::TEMPERATURE(-7.0f32),
+                    measured: -7.0,
+                    limit: -5.0,
+                    direction: Direction::Below,
+                    severity: Severity::Alert,
+                },
+                Violation {
+                    rule: "humidity outside 10 to 90".to_string(),
+                    rule_id: "humidity outside 10 to 90".to_string(),
+                    value: Value::HUMIDITY(95.0f32),
+                    measured: 95.0,
+                    limit: 90.0,
+                    direction: Direction::Above,
+                    severity: Severity::Alert,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn check_measurement_severity() -> () {
+        let rule = |above: f32, severity: Severity| Rule { severity: Some(severity), ..Rule::new("pm10", Some(above), None) };
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            rules: Some(vec![
+                rule(40.0, Severity::Warning),
+                rule(50.0, Severity::Alert),
+                rule(100.0, Severity::Critical),
+            ]),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut data_values = Vec::new();
+        data_values.push(Value::SDS_P1(57.87f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
         };
 
         let res = check_measurement(measurement);
 
         assert_eq!(res.violations.len(), 2);
+        assert_eq!(res.severity, Some(Severity::Alert));
+    }
+
+    #[test]
+    fn check_measurement_mean() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Mean { mean: "24h".to_string(), above: 50.0 }),
+            threshold_pm2: None,
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let now = Utc::now();
+        let mut sensor_state = SensorState::new(&sensor.id);
+        for (hours, value) in vec![(30, 200.0), (20, 40.0), (10, 60.0)] {
+            sensor_state.samples.push(Sample {
+                timestamp: now - Duration::hours(hours),
+                value_type: "pm10".to_string(),
+                value: value,
+            });
+        }
+        let mut data_values = Vec::new();
+        data_values.push(Value::SDS_P1(65.0f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: data_values,
+            timestamp: Some(now),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: now,
+            age: None,
+        };
+
+        let res = check_measurement_with_state(measurement, Some(&sensor_state));
+
+        assert_eq!(res.means, vec![Mean { value: "pm10".to_string(), window: "24h".to_string(), mean: 55.0, samples: 3 }]);
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].measured, 55.0);
+    }
+
+    #[test]
+    fn check_measurement_rate() -> () {
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            rules: Some(vec![
+                Rule { rise: Some(20.0), within: Some("1h".to_string()), ..Rule::new("pm10", None, None) },
+                Rule { fall: Some(5.0), within: Some("1h".to_string()), ..Rule::new("temperature", None, None) },
+            ]),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let now = Utc::now();
+        let mut sensor_state = SensorState::new(&sensor.id);
+        for &(minutes, value_type, value) in &[(90, "pm10", 5.0), (50, "pm10", 10.0), (20, "pm10", 15.0)] {
+            sensor_state.samples.push(Sample {
+                timestamp: now - Duration::minutes(minutes),
+                value_type: value_type.to_string(),
+                value: value,
+            });
+        }
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(35.0), Value::TEMPERATURE(10.0)],
+            timestamp: Some(now),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: now,
+            age: None,
+        };
+
+        let res = check_measurement_with_state(measurement, Some(&sensor_state));
+
+        // There is no previous temperature, so only the change of PM 10 is checked
+        assert_eq!(res.rates.len(), 1);
+        assert_eq!(res.rates[0].delta, 25.0);
+        assert_eq!(res.rates[0].reference, 10.0);
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].rule, "pm10 rise by 20 within 1h");
+        assert_eq!(res.violations[0].measured, 25.0);
+    }
+
+    #[test]
+    fn check_measurement_rate_fall() -> () {
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            rules: Some(vec![Rule { fall: Some(5.0), within: Some("1h".to_string()), ..Rule::new("temperature", None, None) }]),
+            clear_margin: Some(5.0),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let now = Utc::now();
+        let mut sensor_state = SensorState::new(&sensor.id);
+        sensor_state.samples.push(Sample {
+            timestamp: now - Duration::minutes(30),
+            value_type: "temperature".to_string(),
+            value: 18.0,
+        });
+        let measurement = |temperature: f32| Measurement {
+            sensor: sensor.clone(),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::TEMPERATURE(temperature)],
+            timestamp: Some(now),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: now,
+            age: None,
+        };
+
+        let res = check_measurement_with_state(measurement(11.0), Some(&sensor_state));
+
+        assert_eq!(res.rates[0].delta, -7.0);
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].direction, Direction::Below);
+        assert_eq!(res.violations[0].limit, -5.0);
+
+        // The sensor's clear margin does not apply to rates of change
+        sensor_state.record_measurement(&sensor, &res.violations, now);
+        let res = check_measurement_with_state(measurement(14.0), Some(&sensor_state));
+        assert!(res.violations.is_empty());
+    }
+
+    #[test]
+    fn check_measurement_max_exceedance_days() -> () {
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            exceedance_limit: Some(50.0),
+            max_exceedance_days: Some(2),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let now = "2018-03-10T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut sensor_state = SensorState::new(&sensor.id);
+        for days in 1..4 {
+            sensor_state.daily_means_pm10.push(DailyMean {
+                date: (now - Duration::days(days)).naive_utc().date(),
+                sum: 60.0,
+                count: 1,
+                last_reading: now - Duration::days(days),
+            });
+        }
+        let mut data_values = Vec::new();
+        data_values.push(Value::SDS_P1(5.0f32));
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: data_values,
+            timestamp: Some(now),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: now,
+            age: None,
+        };
+
+        let res = check_measurement_with_state(measurement, Some(&sensor_state));
+
+        assert_eq!(res.exceedances.as_ref().map(|e| e.days), Some(3));
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].rule, "max_exceedance_days");
+    }
+
+    #[test]
+    fn check_measurement_aqi_category() -> () {
+        let sensor = Sensor {
+            threshold_pm10: None,
+            threshold_pm2: None,
+            rules: Some(vec![
+                Rule {
+                    category: Some("Unhealthy".to_string()),
+                    ..Rule::new("us_aqi", None, None)
+                },
+                Rule {
+                    category: Some("Very High".to_string()),
+                    ..Rule::new("caqi", None, None)
+                },
+            ]),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(20.0), Value::SDS_P2(60.4)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        let res = check_measurement(measurement);
+
+        assert_eq!(res.us_aqi.as_ref().map(|i| i.value), Some(154));
+        assert_eq!(res.caqi.as_ref().map(|i| &i.category[..]), Some("High"));
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].rule, "us_aqi Unhealthy or worse");
+        assert_eq!(res.violations[0].value, Value::SDS_P2(60.4));
+        assert_eq!(res.violations[0].limit, 150.0);
+    }
+
+    #[test]
+    fn check_measurement_humidity() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Value(50.0)),
+            threshold_pm2: Some(Threshold::Value(25.0)),
+            humidity_correction: Some(HumidityCorrection::KappaKoehler { kappa: 0.5 }),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let measurement = |sensor: &Sensor| Measurement {
+            sensor: sensor.clone(),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(80.0), Value::SDS_P2(30.0), Value::HUMIDITY(90.0)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        // A growth factor of 1 + 0.5 / 1.65 * 0.9 / 0.1 = 3.727
+        let res = check_measurement(measurement(&sensor));
+
+        assert!(res.humidity_corrected);
+        assert!(!res.has_violations);
+        assert_eq!(res.raw_pm10, Some(80.0));
+        assert!((res.pm10.unwrap() - 80.0 / 3.727).abs() < 0.01);
+        assert_eq!(res.humidity, Some(90.0));
+
+        let sensor = Sensor {
+            humidity_correction: None,
+            suppress_above_humidity: Some(85.0),
+            rules: Some(vec![Rule::new("humidity", Some(80.0), None)]),
+            ..sensor
+        };
+        let res = check_measurement(measurement(&sensor));
+
+        assert!(res.humidity_suppressed);
+        assert_eq!(res.violations.len(), 1);
+        assert_eq!(res.violations[0].value, Value::HUMIDITY(90.0));
+    }
+
+    #[test]
+    fn check_measurement_suspect() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Value(50.0)),
+            plausible_ranges: Some(vec![PlausibleRange { value: "pm10".to_string(), min: None, max: Some(999.0) }]),
+            stuck_readings: Some(3),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let measurement = |pm10: f32| Measurement {
+            sensor: sensor.clone(),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(pm10), Value::SDS_P2(20.0)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+
+        let res = check_measurement(measurement(999.9));
+
+        assert!(res.suspect);
+        assert!(!res.has_violations);
+        assert_eq!(res.suspect_reasons, vec!["PM 10 999.9 above plausible maximum 999".to_string()]);
+
+        let mut sensor_state = SensorState::new(&sensor.id);
+        sensor_state.record_particulates(&measurement(60.0), &[60.0, 20.0]);
+        let res = check_measurement_with_state(measurement(60.0), Some(&sensor_state));
+        assert!(!res.suspect);
         assert!(res.has_violations);
 
+        sensor_state.record_particulates(&res.measurement, &res.raw_particulates());
+        let res = check_measurement_with_state(measurement(60.0), Some(&sensor_state));
+        assert!(res.suspect);
+        assert!(!res.has_violations);
+    }
+
+    #[test]
+    fn check_measurement_stuck_same_sample() -> () {
+        let sensor = Sensor { stuck_readings: Some(3), ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost") };
+        let sampled_at = Utc::now();
+        let measurement = |fetched_after: i64, age: Option<u64>| Measurement {
+            sensor: sensor.clone(),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(60.0), Value::SDS_P2(20.0)],
+            timestamp: if age.is_none() { Some(sampled_at) } else { None },
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: sampled_at + Duration::seconds(fetched_after),
+            age: age,
+        };
+
+        // Polling the same sample again does not count as another identical reading
+        let mut sensor_state = SensorState::new(&sensor.id);
+        for &(fetched_after, age) in &[(10, None), (20, None), (30, Some(30)), (40, Some(40))] {
+            let res = check_measurement_with_state(measurement(fetched_after, age), Some(&sensor_state));
+            assert!(!res.suspect);
+            sensor_state.record_particulates(&res.measurement, &res.raw_particulates());
+        }
+        assert_eq!(sensor_state.identical_readings, 1);
+
+        let res = check_measurement_with_state(measurement(200, Some(50)), Some(&sensor_state));
+        assert!(!res.suspect);
+        sensor_state.record_particulates(&res.measurement, &res.raw_particulates());
+        let res = check_measurement_with_state(measurement(400, Some(250)), Some(&sensor_state));
+        assert!(!res.suspect);
+        let res = check_measurement_with_state(measurement(400, Some(100)), Some(&sensor_state));
+        assert!(res.suspect);
+    }
+
+    #[test]
+    fn rule_check_clear_margin() -> () {
+        let rule = Rule { clear_margin: Some(5.0), ..Rule::new("pm10", Some(50.0), Some(10.0)) };
+
+        assert!(rule.check(&Value::SDS_P1(48.0), None).is_none());
+        assert!(rule.check(&Value::SDS_P1(48.0), Some(Direction::Above)).is_some());
+        assert!(rule.check(&Value::SDS_P1(44.0), Some(Direction::Above)).is_none());
+    }
+
+    #[test]
+    fn rule_check_clear_margin_two_sided() -> () {
+        let rule = Rule { clear_margin: Some(5.0), ..Rule::new("pm10", Some(50.0), Some(10.0)) };
+
+        // Only the violated limit is widened by the clear margin
+        assert!(rule.check(&Value::SDS_P1(47.0), Some(Direction::Below)).is_none());
+        assert_eq!(rule.check(&Value::SDS_P1(12.0), Some(Direction::Below)).map(|v| v.direction), Some(Direction::Below));
+        assert!(rule.check(&Value::SDS_P1(12.0), Some(Direction::Above)).is_none());
+        assert_eq!(rule.check(&Value::SDS_P1(47.0), Some(Direction::Above)).map(|v| v.direction), Some(Direction::Above));
+    }
+
+    #[test]
+    fn rule_id_unique() -> () {
+        let a = Rule { name: Some("Too hot".to_string()), ..Rule::new("BME280_temperature", Some(30.0), None) };
+        let b = Rule { name: Some("Too hot".to_string()), ..Rule::new("BME280_temperature", Some(35.0), None) };
+
+        assert_eq!(a.name(), b.name());
+        assert_ne!(a.id(), b.id());
+    }
+
+    #[test]
+    fn rule_select_value_type() -> () {
+        let rule = Rule::new("BME280_temperature", Some(30.0), None);
+
+        assert_eq!(rule.select(&Value::BME280_TEMPERATURE(31.0)), Some(31.0));
+        assert_eq!(rule.select(&Value::TEMPERATURE(31.0)), None);
+        assert_eq!(Rule::new("pm10", Some(50.0), None).select(&Value::SPS30_P1(12.0)), Some(12.0));
     }
 }
@@ -1,7 +1,17 @@
+use aqi;
+use check::{HumidityCorrection, PlausibleRange, Rule, Threshold};
+use drift::Colocation;
+use group::{Aggregation, Group};
+use measurement::Value;
 use sensor::{ Sensor};
+use source::{self, Source};
 
+use chrono::Duration;
+use handlebars::Handlebars;
+use hyper::Uri;
 use lettre::transport::smtp::authentication;
 use serde::de::{self, Deserializer, Visitor};
+use std::collections::HashSet;
 use std::fs::File;
 use std::fmt;
 use std::io::Read;
@@ -10,6 +20,22 @@ use toml;
 
 error_chain! {
 	errors {
+		InvalidSensorUri(sensor: String, key: String, uri: String, reason: String) {
+			description("invalid sensor uri")
+			display("invalid {} '{}' for {}: {}", key, uri, sensor, reason)
+		}
+		InvalidValue(path: String, reason: String) {
+			description("invalid configuration value")
+			display("invalid value for '{}': {}", path, reason)
+		}
+		DuplicateValue(path: String, value: String) {
+			description("duplicate configuration value")
+			display("duplicate value '{}' for '{}'", value, path)
+		}
+		InvalidTemplate(path: String, reason: String) {
+			description("invalid template")
+			display("invalid template for '{}': {}", path, reason)
+		}
 	}
 	foreign_links {
 		CouldNotRead(::std::io::Error);
@@ -17,9 +43,51 @@ error_chain! {
 	}
 }
 
+pub const DEFAULT_INTERVAL: u64 = 300;
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_READ_TIMEOUT: u64 = 30;
+pub const DEFAULT_RETRIES: u32 = 0;
+pub const DEFAULT_RETRY_BACKOFF: u64 = 1;
+// Reading all sensors waits for the slowest one, so retries must not stall a run for long
+pub const MAX_RETRIES: u32 = 5;
+pub const MAX_RETRY_BACKOFF: u64 = 60;
+pub const DEFAULT_MAX_REDIRECTS: u32 = 5;
+pub const DEFAULT_API_URI: &str = "https://api.luftdaten.info/v1/sensor/";
+pub const DEFAULT_EXCEEDANCE_LIMIT: f32 = 50.0;
+// Means and rates of change keep their readings in the sensor state unless there is a history, so their time windows are limited
+pub const MAX_STATE_WINDOW_DAYS: i64 = 7;
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct General {
     pub state_dir: Option<String>,
+    pub interval: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub max_redirects: Option<u32>,
+    pub api_uri: Option<String>,
+    pub strict: Option<bool>,
+    pub history_retention: Option<String>,
+}
+
+// Durations are given as a number and a unit, e.g., '30m', '24h', '7d', or '1y'
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = duration.split_at(split);
+    let number = number.parse::<i64>().ok()?;
+    match unit.trim() {
+        _ if number <= 0 => None,
+        "s" => Some(Duration::seconds(number)),
+        "m" => Some(Duration::minutes(number)),
+        "h" => Some(Duration::hours(number)),
+        "d" => Some(Duration::days(number)),
+        "w" => Some(Duration::weeks(number)),
+        "y" => Some(Duration::days(number * 365)),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,13 +101,32 @@ pub enum NotificationCondition {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Defaults {
-    pub threshold_pm10: Option<f32>,
-    pub threshold_pm2: Option<f32>,
+    pub threshold_pm10: Option<Threshold>,
+    pub threshold_pm2: Option<Threshold>,
+    pub threshold_pm1: Option<Threshold>,
+    pub rules: Option<Vec<Rule>>,
+    pub clear_margin: Option<f32>,
+    pub hold_readings: Option<u32>,
     pub notification_condition: Option<NotificationCondition>,
+    pub notify_on_failure: Option<bool>,
+    pub verify_certificate: Option<bool>,
+    pub ca_file: Option<String>,
+    pub unreachable_after_failures: Option<u32>,
+    pub unreachable_after: Option<u64>,
+    pub max_age: Option<u64>,
+    pub exceedance_limit: Option<f32>,
+    pub max_exceedance_days: Option<u32>,
+    pub humidity_correction: Option<HumidityCorrection>,
+    pub suppress_above_humidity: Option<f32>,
+    pub plausible_ranges: Option<Vec<PlausibleRange>>,
+    pub stuck_readings: Option<u32>,
+    pub notify_on_suspect: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Smtp {
     pub sender: String,
     pub receiver: String,
@@ -48,12 +135,20 @@ pub struct Smtp {
     pub port: Option<u16>,
     pub username: Option<String>,
     pub password: Option<String>,
-    #[serde(deserialize_with = "auth_mechanism")]
+    #[serde(default, deserialize_with = "auth_mechanism")]
     pub auth_mechanism: Option<authentication::Mechanism>,
     #[serde(default = "default_template")]
     pub text_template: String,
     #[serde(default = "default_template")]
     pub html_template: String,
+    #[serde(default = "default_failure_subject")]
+    pub failure_subject: String,
+    #[serde(default = "default_failure_template")]
+    pub failure_template: String,
+    #[serde(default = "default_maintenance_subject")]
+    pub maintenance_subject: String,
+    #[serde(default = "default_maintenance_template")]
+    pub maintenance_template: String,
 }
 
 //fn deserialize_u64_or_empty_string<D>(deserializer: &mut D) -> Result<u64, D::Error> where D: Deserializer
@@ -91,12 +186,253 @@ fn default_template() -> String {
     "{{ sensor.name }}".to_string()
 }
 
+fn default_failure_subject() -> String {
+    "Luftpost: Reading sensor {{ sensor.name }} failed".to_string()
+}
+
+fn default_failure_template() -> String {
+    "Reading a measurement from sensor {{ sensor.name }} ({{ sensor.id }}) failed: {{ error }}".to_string()
+}
+
+fn default_maintenance_subject() -> String {
+    "Luftpost: {{#if suspect}}Sensor {{ sensor.name }} seems to malfunction{{else}}Co-located sensors {{ colocation.name }} {{#if drifting}}drift apart{{else}}agree again{{/if}}{{/if}}"
+        .to_string()
+}
+
+fn default_maintenance_template() -> String {
+    "{{#if suspect}}The readings of sensor {{ sensor.name }} ({{ sensor.id }}) are suspect: {{#each suspect_reasons as |r|}}{{ r }}; {{/each}}{{else}}\
+     The {{ value }} readings of the co-located sensors {{ colocation.name }} ({{ colocation.id }}) {{#if drifting}}drift apart{{else}}agree again{{/if}}: \
+     {{#each readings as |r|}}{{ r.name }} {{number r.value}}{{#if r.mean}} (mean {{number r.mean}}){{/if}}; {{/each}}\
+     difference {{number difference}}{{#if ratio}}, ratio {{number ratio}}{{/if}}\
+     {{#if mean_difference}}, mean difference {{number mean_difference}}{{/if}}{{#if mean_ratio}}, mean ratio {{number mean_ratio}}{{/if}}{{/if}}"
+        .to_string()
+}
+
+fn validate_smtp(smtp: &Smtp) -> Result<()> {
+    if smtp.auth_mechanism.is_some() && (smtp.username.is_none() || smtp.password.is_none()) {
+        bail!(ErrorKind::InvalidValue("smtp.auth_mechanism".to_string(), "requires 'smtp.username' and 'smtp.password'".to_string()));
+    }
+    if smtp.username.is_some() != smtp.password.is_some() {
+        bail!(ErrorKind::InvalidValue("smtp.username".to_string(), "requires both 'smtp.username' and 'smtp.password'".to_string()));
+    }
+
+    let templates = [
+        ("smtp.subject", &smtp.subject),
+        ("smtp.text_template", &smtp.text_template),
+        ("smtp.html_template", &smtp.html_template),
+        ("smtp.failure_subject", &smtp.failure_subject),
+        ("smtp.failure_template", &smtp.failure_template),
+        ("smtp.maintenance_subject", &smtp.maintenance_subject),
+        ("smtp.maintenance_template", &smtp.maintenance_template),
+    ];
+    let mut handlebars = Handlebars::new();
+    for &(path, template) in &templates {
+        if let Err(e) = handlebars.register_template_string(path, template) {
+            bail!(ErrorKind::InvalidTemplate(path.to_string(), e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_threshold(path: &str, threshold: Option<f32>) -> Result<()> {
+    match threshold {
+        Some(t) if t < 0.0 || t.is_nan() => bail!(ErrorKind::InvalidValue(path.to_string(), format!("threshold {} must not be negative", t))),
+        _ => Ok(()),
+    }
+}
+
+fn validate_duration(path: &str, duration: Option<&str>) -> Result<()> {
+    match duration {
+        Some(duration) if parse_duration(duration).is_none() => {
+            bail!(ErrorKind::InvalidValue(path.to_string(), format!("'{}' is not a duration like '30m', '24h', '7d', or '1y'", duration)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_state_window(path: &str, window: Option<&str>, max_window: Duration) -> Result<()> {
+    match window.and_then(parse_duration) {
+        Some(duration) if duration > max_window => {
+            bail!(ErrorKind::InvalidValue(path.to_string(), format!("time windows must not exceed {}d", max_window.num_days())))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_value_type(path: &str, value: &str) -> Result<()> {
+    let generic = ["pm10", "pm2", "pm1"].contains(&&value.to_lowercase()[..]);
+    if let Value::UNKNOWN(_, _) = Value::from((&value.to_lowercase()[..], 0.0)) {
+        if !generic {
+            bail!(ErrorKind::InvalidValue(path.to_string(), format!("unknown value type '{}'", value)));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_plausible_ranges(path: &str, ranges: &Option<Vec<PlausibleRange>>) -> Result<()> {
+    let ranges = match *ranges {
+        Some(ref ranges) => ranges,
+        None => return Ok(()),
+    };
+    for (i, range) in ranges.iter().enumerate() {
+        let path = |key: &str| format!("{}[{}].{}", path, i, key);
+        validate_value_type(&path("value"), &range.value)?;
+        match (range.min, range.max) {
+            (None, None) => bail!(ErrorKind::InvalidValue(path("min"), "either 'min' or 'max' is required".to_string())),
+            (Some(min), Some(max)) if min > max => {
+                bail!(ErrorKind::InvalidValue(path("min"), format!("minimum {} must not exceed maximum {}", min, max)))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_rules(path: &str, rules: &Option<Vec<Rule>>, has_state_dir: bool, max_window: Duration) -> Result<()> {
+    let rules = match *rules {
+        Some(ref rules) => rules,
+        None => return Ok(()),
+    };
+    for (i, rule) in rules.iter().enumerate() {
+        let path = |key: &str| format!("{}[{}].{}", path, i, key);
+        let value = rule.value.to_lowercase();
+        let generic = value == "pm10" || value == "pm2" || value == "pm1";
+        let index = value == aqi::US_AQI || value == aqi::CAQI;
+        if let Value::UNKNOWN(_, _) = Value::from((&value[..], 0.0)) {
+            if !generic && !index {
+                bail!(ErrorKind::InvalidValue(path("value"), format!("unknown value type '{}'", rule.value)));
+            }
+        }
+        validate_threshold(&path("clear_margin"), rule.clear_margin)?;
+        validate_duration(&path("mean"), rule.mean.as_ref().map(|m| &m[..]))?;
+        validate_state_window(&path("mean"), rule.mean.as_ref().map(|m| &m[..]), max_window)?;
+        if rule.mean.is_some() && !has_state_dir {
+            bail!(ErrorKind::InvalidValue(path("mean"), "means require 'general.state_dir'".to_string()));
+        }
+        if rule.mean.is_some() && index {
+            bail!(ErrorKind::InvalidValue(path("mean"), "means are not supported for air quality indices".to_string()));
+        }
+        validate_threshold(&path("rise"), rule.rise)?;
+        validate_threshold(&path("fall"), rule.fall)?;
+        validate_duration(&path("within"), rule.within.as_ref().map(|w| &w[..]))?;
+        validate_state_window(&path("within"), rule.within.as_ref().map(|w| &w[..]), max_window)?;
+        if rule.within.is_some() {
+            if !has_state_dir {
+                bail!(ErrorKind::InvalidValue(path("within"), "rates of change require 'general.state_dir'".to_string()));
+            }
+            if index {
+                bail!(ErrorKind::InvalidValue(path("within"), "rates of change are not supported for air quality indices".to_string()));
+            }
+            if rule.above.is_some() || rule.below.is_some() || rule.mean.is_some() || rule.category.is_some() {
+                bail!(ErrorKind::InvalidValue(path("within"), "rates of change must not set 'above', 'below', 'mean', or 'category'".to_string()));
+            }
+            if rule.rise.is_none() && rule.fall.is_none() {
+                bail!(ErrorKind::InvalidValue(path("within"), "either 'rise' or 'fall' is required".to_string()));
+            }
+        } else if rule.rise.is_some() || rule.fall.is_some() {
+            let key = if rule.rise.is_some() { "rise" } else { "fall" };
+            bail!(ErrorKind::InvalidValue(path(key), "requires the time window 'within'".to_string()));
+        }
+        if let Some(ref category) = rule.category {
+            if !index {
+                bail!(ErrorKind::InvalidValue(path("category"), "categories require 'us_aqi' or 'caqi' as value".to_string()));
+            }
+            if aqi::category_limit(&value, category).is_none() {
+                let categories = aqi::category_names(&value)[1..].join("', '");
+                bail!(ErrorKind::InvalidValue(path("category"), format!("invalid category '{}', expected one of '{}'", category, categories)));
+            }
+        }
+        match (rule.above, rule.below) {
+            (None, None) if rule.category.is_some() || rule.is_rate() => {}
+            (None, None) => bail!(ErrorKind::InvalidValue(path("above"), "either 'above' or 'below' is required".to_string())),
+            (Some(above), Some(below)) if below > above => {
+                bail!(ErrorKind::InvalidValue(path("below"), format!("{} must not be greater than 'above' {}", below, above)))
+            }
+            _ => {}
+        }
+        // Levels of the same value must be ordered by severity, e.g., warning at 40, alert at 50, and critical at 100
+        for other in rules[..i].iter().filter(|other| other.value.to_lowercase() == value) {
+            let (lower, higher) = if other.severity() < rule.severity() { (other, rule) } else { (rule, other) };
+            if lower.severity() == higher.severity() {
+                continue;
+            }
+            let above = |r: &Rule| r.above.or_else(|| r.category.as_ref().and_then(|c| aqi::category_limit(&r.value, c)));
+            if let (Some(lower_above), Some(higher_above)) = (above(lower), above(higher)) {
+                if higher_above < lower_above {
+                    bail!(ErrorKind::InvalidValue(path("above"), format!("{} level must not be below {} level", higher.severity(), lower.severity())));
+                }
+            }
+            if let (Some(lower_below), Some(higher_below)) = (lower.below, higher.below) {
+                if higher_below > lower_below {
+                    bail!(ErrorKind::InvalidValue(path("below"), format!("{} level must not be above {} level", higher.severity(), lower.severity())));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_humidity_correction(path: &str, correction: Option<HumidityCorrection>) -> Result<()> {
+    match correction {
+        Some(HumidityCorrection::KappaKoehler { kappa }) if !(kappa > 0.0 && kappa.is_finite()) => {
+            bail!(ErrorKind::InvalidValue(format!("{}.kappa", path), format!("{} must be greater than 0", kappa)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_humidity(path: &str, humidity: Option<f32>) -> Result<()> {
+    match humidity {
+        Some(h) if !(h > 0.0 && h <= 100.0) => bail!(ErrorKind::InvalidValue(path.to_string(), format!("{} must be a relative humidity between 0 and 100", h))),
+        _ => Ok(()),
+    }
+}
+
+fn validate_positive(path: &str, value: Option<u64>) -> Result<()> {
+    match value {
+        Some(0) => bail!(ErrorKind::InvalidValue(path.to_string(), "must be greater than 0".to_string())),
+        _ => Ok(()),
+    }
+}
+
+fn validate_at_most(path: &str, value: Option<u64>, max: u64) -> Result<()> {
+    match value {
+        Some(v) if v > max => bail!(ErrorKind::InvalidValue(path.to_string(), format!("{} must be at most {}", v, max))),
+        _ => Ok(()),
+    }
+}
+
+fn validate_uri(sensor: &str, key: &str, uri_str: &str) -> Result<Uri> {
+    let invalid = |reason: &str| ErrorKind::InvalidSensorUri(sensor.to_string(), key.to_string(), uri_str.to_string(), reason.to_string());
+
+    let uri: Uri = uri_str.parse().map_err(|e: ::hyper::error::UriError| invalid(&e.to_string()))?;
+    match uri.scheme() {
+        Some("http") | Some("https") => {},
+        Some(scheme) => bail!(invalid(&format!("unsupported scheme '{}'", scheme))),
+        None => bail!(invalid("missing scheme")),
+    }
+    if uri.host().is_none() {
+        bail!(invalid("missing host"));
+    }
+
+    Ok(uri)
+}
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub general: General,
     pub defaults: Defaults,
     pub smtp: Option<Smtp>,
     pub sensors: Vec<Sensor>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub colocations: Vec<Colocation>,
 }
 
 impl Config {
@@ -115,42 +451,457 @@ impl Config {
     }
 
     fn parse_toml(content: &str) -> Result<Config> {
-        let config: Config = toml::from_str(content)?;
+        let mut config: Config = toml::from_str(content)?;
+        let data_uris = config.validate()?;
+        for (sensor, data_uri) in config.sensors.iter_mut().zip(data_uris) {
+            sensor.parsed_data_uri = data_uri;
+        }
 
         let config = Config::set_defaults(config);
 
         Ok(config)
     }
 
+    // Returns the validated data URIs of the sensors so that they are used as validated
+    fn validate(&self) -> Result<Vec<Option<Uri>>> {
+        self.validate_general()?;
+        self.validate_defaults()?;
+        let data_uris = self.validate_sensors()?;
+        self.validate_groups()?;
+        self.validate_colocations()?;
+        if let Some(ref smtp) = self.smtp {
+            validate_smtp(smtp)?;
+        }
+
+        Ok(data_uris)
+    }
+
+    fn validate_general(&self) -> Result<()> {
+        let general = &self.general;
+        validate_positive("general.interval", general.interval)?;
+        validate_positive("general.connect_timeout", general.connect_timeout)?;
+        validate_positive("general.read_timeout", general.read_timeout)?;
+        validate_at_most("general.retries", general.retries.map(u64::from), u64::from(MAX_RETRIES))?;
+        validate_positive("general.retry_backoff", general.retry_backoff)?;
+        validate_at_most("general.retry_backoff", general.retry_backoff, MAX_RETRY_BACKOFF)?;
+        if let Some(ref api_uri) = general.api_uri {
+            validate_uri("general", "api_uri", api_uri)?;
+        }
+        validate_duration("general.history_retention", general.history_retention.as_ref().map(|r| &r[..]))?;
+        if general.history_retention.is_some() && general.state_dir.is_none() {
+            bail!(ErrorKind::InvalidValue("general.history_retention".to_string(), "the history requires 'general.state_dir'".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn validate_defaults(&self) -> Result<()> {
+        let defaults = &self.defaults;
+        self.validate_pm_threshold("defaults.threshold_pm10", &defaults.threshold_pm10, self.max_window())?;
+        self.validate_pm_threshold("defaults.threshold_pm2", &defaults.threshold_pm2, self.max_window())?;
+        self.validate_pm_threshold("defaults.threshold_pm1", &defaults.threshold_pm1, self.max_window())?;
+        validate_positive("defaults.max_age", defaults.max_age)?;
+        validate_threshold("defaults.exceedance_limit", defaults.exceedance_limit)?;
+        self.validate_max_exceedance_days("defaults.max_exceedance_days", defaults.max_exceedance_days)?;
+        validate_humidity_correction("defaults.humidity_correction", defaults.humidity_correction)?;
+        validate_humidity("defaults.suppress_above_humidity", defaults.suppress_above_humidity)?;
+        validate_plausible_ranges("defaults.plausible_ranges", &defaults.plausible_ranges)?;
+        self.validate_stuck_readings("defaults.stuck_readings", defaults.stuck_readings)?;
+        validate_rules("defaults.rules", &defaults.rules, self.general.state_dir.is_some(), self.max_window())?;
+        validate_threshold("defaults.clear_margin", defaults.clear_margin)?;
+        validate_positive("defaults.hold_readings", defaults.hold_readings.map(u64::from))?;
+        self.validate_notification_condition("defaults.notification_condition", defaults.notification_condition)?;
+
+        Ok(())
+    }
+
+    fn validate_sensors(&self) -> Result<Vec<Option<Uri>>> {
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+        let mut stdin_sensors = 0;
+        let mut data_uris = Vec::new();
+
+        for (i, s) in self.sensors.iter().enumerate() {
+            let sensor = format!("sensors[{}] ('{}')", i, s.name);
+            validate_uri(&sensor, "ui_uri", &s.ui_uri)?;
+            if let Some(ref api_uri) = s.api_uri {
+                validate_uri(&sensor, "api_uri", api_uri)?;
+            }
+
+            let path = |key: &str| format!("sensors[{}].{}", i, key);
+            let sources = [s.source.is_some(), s.data_uri.is_some(), s.api_id.is_some()];
+            match sources.iter().filter(|x| **x).count() {
+                0 => bail!(ErrorKind::InvalidValue(path("source"), "one of 'source', 'data_uri' or 'api_id' is required".to_string())),
+                1 => {}
+                _ => bail!(ErrorKind::InvalidValue(path("source"), "only one of 'source', 'data_uri' or 'api_id' may be set".to_string())),
+            }
+            // 'data_uri' and 'api_id' are shorthands for the corresponding source kinds
+            let key = |shorthand: &str, source_key: &str| if s.source.is_some() { format!("source.{}", source_key) } else { shorthand.to_string() };
+            let data_uri = match s.source() {
+                Some(Source::Http { ref uri }) => Some(validate_uri(&sensor, &key("data_uri", "uri"), uri)?),
+                Some(Source::Api { ref id, ref uri }) => {
+                    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+                        bail!(ErrorKind::InvalidValue(path(&key("api_id", "id")), format!("'{}' is not a numeric sensor id", id)));
+                    }
+                    if let Some(ref uri) = *uri {
+                        validate_uri(&sensor, "source.uri", uri)?;
+                    }
+                    let api_uri = uri.as_ref().or_else(|| s.api_uri.as_ref()).or_else(|| self.general.api_uri.as_ref());
+                    let api_data_uri = source::api_data_uri(api_uri.map(|u| &u[..]), id);
+                    Some(validate_uri(&sensor, &key("api_id", "id"), &api_data_uri)?)
+                }
+                Some(Source::File { ref path }) if path.is_empty() => {
+                    bail!(ErrorKind::InvalidValue(format!("sensors[{}].source.path", i), "must not be empty".to_string()));
+                }
+                Some(Source::Stdin) => {
+                    stdin_sensors += 1;
+                    if stdin_sensors > 1 {
+                        bail!(ErrorKind::InvalidValue(path("source"), "stdin may only be used by one sensor".to_string()));
+                    }
+                    None
+                }
+                _ => None,
+            };
+            data_uris.push(data_uri);
+            if !ids.insert(&s.id) {
+                bail!(ErrorKind::DuplicateValue(path("id"), s.id.clone()));
+            }
+            if !names.insert(&s.name) {
+                bail!(ErrorKind::DuplicateValue(path("name"), s.name.clone()));
+            }
+            self.validate_pm_threshold(&path("threshold_pm10"), &s.threshold_pm10, self.max_window())?;
+            self.validate_pm_threshold(&path("threshold_pm2"), &s.threshold_pm2, self.max_window())?;
+            self.validate_pm_threshold(&path("threshold_pm1"), &s.threshold_pm1, self.max_window())?;
+            validate_rules(&path("rules"), &s.rules, self.general.state_dir.is_some(), self.max_window())?;
+            validate_threshold(&path("clear_margin"), s.clear_margin)?;
+            validate_positive(&path("hold_readings"), s.hold_readings.map(u64::from))?;
+            validate_positive(&path("interval"), s.interval)?;
+            validate_positive(&path("max_age"), s.max_age)?;
+            validate_threshold(&path("exceedance_limit"), s.exceedance_limit)?;
+            self.validate_max_exceedance_days(&path("max_exceedance_days"), s.max_exceedance_days)?;
+            validate_humidity_correction(&path("humidity_correction"), s.humidity_correction)?;
+            validate_humidity(&path("suppress_above_humidity"), s.suppress_above_humidity)?;
+            validate_plausible_ranges(&path("plausible_ranges"), &s.plausible_ranges)?;
+            self.validate_stuck_readings(&path("stuck_readings"), s.stuck_readings)?;
+            validate_positive(&path("connect_timeout"), s.connect_timeout)?;
+            validate_positive(&path("read_timeout"), s.read_timeout)?;
+            validate_at_most(&path("retries"), s.retries.map(u64::from), u64::from(MAX_RETRIES))?;
+            validate_positive(&path("retry_backoff"), s.retry_backoff)?;
+            validate_at_most(&path("retry_backoff"), s.retry_backoff, MAX_RETRY_BACKOFF)?;
+            self.validate_notification_condition(&path("notification_condition"), s.notification_condition)?;
+        }
+
+        Ok(data_uris)
+    }
+
+    fn validate_groups(&self) -> Result<()> {
+        let mut ids = self.sensors.iter().map(|s| &s.id).collect::<HashSet<_>>();
+        let mut names = self.sensors.iter().map(|s| &s.name).collect::<HashSet<_>>();
+
+        for (i, g) in self.groups.iter().enumerate() {
+            let path = |key: &str| format!("groups[{}].{}", i, key);
+            // Groups share the state directory and the notifications with the sensors
+            if !ids.insert(&g.id) {
+                bail!(ErrorKind::DuplicateValue(path("id"), g.id.clone()));
+            }
+            if !names.insert(&g.name) {
+                bail!(ErrorKind::DuplicateValue(path("name"), g.name.clone()));
+            }
+            if g.sensors.is_empty() {
+                bail!(ErrorKind::InvalidValue(path("sensors"), "must not be empty".to_string()));
+            }
+            for (j, id) in g.sensors.iter().enumerate() {
+                if !self.sensors.iter().any(|s| &s.id == id) {
+                    bail!(ErrorKind::InvalidValue(format!("groups[{}].sensors[{}]", i, j), format!("unknown sensor id '{}'", id)));
+                }
+            }
+            let at_least = match g.aggregation {
+                Some(Aggregation::AtLeast { count }) if count == 0 || count > g.sensors.len() => {
+                    bail!(ErrorKind::InvalidValue(path("aggregation.count"), format!("{} must be between 1 and the number of sensors", count)))
+                }
+                Some(Aggregation::AtLeast { .. }) => true,
+                _ => false,
+            };
+            // Groups are not recorded in the history
+            self.validate_pm_threshold(&path("threshold_pm10"), &g.threshold_pm10, Duration::days(MAX_STATE_WINDOW_DAYS))?;
+            self.validate_pm_threshold(&path("threshold_pm2"), &g.threshold_pm2, Duration::days(MAX_STATE_WINDOW_DAYS))?;
+            self.validate_pm_threshold(&path("threshold_pm1"), &g.threshold_pm1, Duration::days(MAX_STATE_WINDOW_DAYS))?;
+            validate_rules(&path("rules"), &g.rules, self.general.state_dir.is_some(), Duration::days(MAX_STATE_WINDOW_DAYS))?;
+            validate_threshold(&path("clear_margin"), g.clear_margin)?;
+            validate_positive(&path("hold_readings"), g.hold_readings.map(u64::from))?;
+            self.validate_notification_condition(&path("notification_condition"), g.notification_condition)?;
+            validate_positive(&path("max_age"), g.max_age)?;
+            validate_humidity_correction(&path("humidity_correction"), g.humidity_correction)?;
+            validate_humidity(&path("suppress_above_humidity"), g.suppress_above_humidity)?;
+            // Members are checked one by one, so there are no means or rates of change of the group
+            if at_least {
+                let thresholds = [("threshold_pm10", &g.threshold_pm10), ("threshold_pm2", &g.threshold_pm2), ("threshold_pm1", &g.threshold_pm1)];
+                if let Some(&(key, _)) = thresholds.iter().find(|&&(_, t)| t.as_ref().and_then(|t| t.mean()).is_some()) {
+                    bail!(ErrorKind::InvalidValue(path(key), "means are not supported for 'AtLeast' groups".to_string()));
+                }
+                let rules = g.rules.as_ref().map(|r| &r[..]).unwrap_or(&[]);
+                if let Some(j) = rules.iter().position(|r| r.mean.is_some() || r.is_rate()) {
+                    bail!(ErrorKind::InvalidValue(format!("groups[{}].rules[{}]", i, j), "means and rates of change are not supported for 'AtLeast' groups".to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_colocations(&self) -> Result<()> {
+        let mut ids = self.sensors.iter().map(|s| &s.id).chain(self.groups.iter().map(|g| &g.id)).collect::<HashSet<_>>();
+        let mut names = self.sensors.iter().map(|s| &s.name).chain(self.groups.iter().map(|g| &g.name)).collect::<HashSet<_>>();
+
+        for (i, c) in self.colocations.iter().enumerate() {
+            let path = |key: &str| format!("colocations[{}].{}", i, key);
+            // Co-locations keep their maintenance state next to the sensors' states
+            if !ids.insert(&c.id) {
+                bail!(ErrorKind::DuplicateValue(path("id"), c.id.clone()));
+            }
+            if !names.insert(&c.name) {
+                bail!(ErrorKind::DuplicateValue(path("name"), c.name.clone()));
+            }
+            if c.sensors.len() < 2 {
+                bail!(ErrorKind::InvalidValue(path("sensors"), "at least two sensors are required".to_string()));
+            }
+            for (j, id) in c.sensors.iter().enumerate() {
+                if !self.sensors.iter().any(|s| &s.id == id) {
+                    bail!(ErrorKind::InvalidValue(format!("colocations[{}].sensors[{}]", i, j), format!("unknown sensor id '{}'", id)));
+                }
+            }
+            if let Some(ref value) = c.value {
+                validate_value_type(&path("value"), value)?;
+            }
+            if c.max_ratio.is_none() && c.max_difference.is_none() {
+                bail!(ErrorKind::InvalidValue(path("max_ratio"), "either 'max_ratio' or 'max_difference' is required".to_string()));
+            }
+            match c.max_ratio {
+                Some(r) if r < 1.0 || r.is_nan() => bail!(ErrorKind::InvalidValue(path("max_ratio"), format!("ratio {} must be at least 1", r))),
+                _ => {}
+            }
+            validate_threshold(&path("max_difference"), c.max_difference)?;
+            validate_threshold(&path("min_value"), c.min_value)?;
+            validate_duration(&path("window"), c.window.as_ref().map(|w| &w[..]))?;
+            if c.window.is_some() && self.general.history_retention.is_none() {
+                bail!(ErrorKind::InvalidValue(path("window"), "comparing over time requires 'general.history_retention'".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The history holds the readings for time windows up to its retention
+    fn max_window(&self) -> Duration {
+        let retention = self.general.history_retention.as_ref().and_then(|r| parse_duration(r));
+        let max_window = Duration::days(MAX_STATE_WINDOW_DAYS);
+        retention.map(|r| r.max(max_window)).unwrap_or(max_window)
+    }
+
+    fn validate_pm_threshold(&self, path: &str, threshold: &Option<Threshold>, max_window: Duration) -> Result<()> {
+        if let Some(ref threshold) = *threshold {
+            validate_threshold(path, Some(threshold.above()))?;
+            validate_duration(&format!("{}.mean", path), threshold.mean())?;
+            validate_state_window(&format!("{}.mean", path), threshold.mean(), max_window)?;
+            if threshold.mean().is_some() && self.general.state_dir.is_none() {
+                bail!(ErrorKind::InvalidValue(format!("{}.mean", path), "means require 'general.state_dir'".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_max_exceedance_days(&self, path: &str, max_exceedance_days: Option<u32>) -> Result<()> {
+        if max_exceedance_days.is_some() && self.general.state_dir.is_none() {
+            bail!(ErrorKind::InvalidValue(path.to_string(), "counting exceedance days requires 'general.state_dir'".to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn validate_stuck_readings(&self, path: &str, stuck_readings: Option<u32>) -> Result<()> {
+        match stuck_readings {
+            Some(readings) if readings < 2 => bail!(ErrorKind::InvalidValue(path.to_string(), format!("{} must be at least 2", readings))),
+            Some(_) if self.general.state_dir.is_none() => {
+                bail!(ErrorKind::InvalidValue(path.to_string(), "detecting stuck values requires 'general.state_dir'".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_notification_condition(&self, path: &str, condition: Option<NotificationCondition>) -> Result<()> {
+        if condition == Some(NotificationCondition::OnChange) && self.general.state_dir.is_none() {
+            bail!(ErrorKind::InvalidValue(path.to_string(), "'OnChange' requires 'general.state_dir'".to_string()));
+        }
+
+        Ok(())
+    }
+
     fn set_defaults(config: Config) -> Config {
-        let threshold_pm10 = config.defaults.threshold_pm10.or(Some(50.0));
-        let threshold_pm2 = config.defaults.threshold_pm2.or(Some(50.0));
+        let interval = config.general.interval.or(Some(DEFAULT_INTERVAL));
+        let connect_timeout = config.general.connect_timeout.or(Some(DEFAULT_CONNECT_TIMEOUT));
+        let read_timeout = config.general.read_timeout.or(Some(DEFAULT_READ_TIMEOUT));
+        let retries = config.general.retries.or(Some(DEFAULT_RETRIES));
+        let retry_backoff = config.general.retry_backoff.or(Some(DEFAULT_RETRY_BACKOFF));
+        let max_redirects = config.general.max_redirects.or(Some(DEFAULT_MAX_REDIRECTS));
+        let ca_file = config.defaults.ca_file.clone();
+        let api_uri = config.general.api_uri.clone().or_else(|| Some(DEFAULT_API_URI.to_string()));
+        let strict = config.general.strict.or(Some(false));
+        let threshold_pm10 = config.defaults.threshold_pm10.clone().or(Some(Threshold::Value(50.0)));
+        let threshold_pm2 = config.defaults.threshold_pm2.clone().or(Some(Threshold::Value(50.0)));
+        let threshold_pm1 = config.defaults.threshold_pm1.clone();
+        let rules = config.defaults.rules.clone();
+        let clear_margin = config.defaults.clear_margin;
+        let hold_readings = config.defaults.hold_readings;
         let e_mail_condition = config.defaults.notification_condition.or(Some(NotificationCondition::ThresholdExceeded));
+        let notify_on_failure = config.defaults.notify_on_failure.or(Some(false));
+        let verify_certificate = config.defaults.verify_certificate.or(Some(true));
+        let unreachable_after_failures = config.defaults.unreachable_after_failures;
+        let unreachable_after = config.defaults.unreachable_after;
+        let max_age = config.defaults.max_age;
+        let exceedance_limit = config.defaults.exceedance_limit.or(Some(DEFAULT_EXCEEDANCE_LIMIT));
+        let max_exceedance_days = config.defaults.max_exceedance_days;
+        let humidity_correction = config.defaults.humidity_correction;
+        let suppress_above_humidity = config.defaults.suppress_above_humidity;
+        let plausible_ranges = config.defaults.plausible_ranges.clone();
+        let stuck_readings = config.defaults.stuck_readings;
+        let notify_on_suspect = config.defaults.notify_on_suspect.or(Some(false));
 
         let sensors = config
             .sensors
             .into_iter()
             .map(|s| {
-                let s_threshold_pm10 = s.threshold_pm10.or(threshold_pm10);
-                let s_threshold_pm2 = s.threshold_pm2.or(threshold_pm2);
+                let s_threshold_pm10 = s.threshold_pm10.or_else(|| threshold_pm10.clone());
+                let s_threshold_pm2 = s.threshold_pm2.or_else(|| threshold_pm2.clone());
+                let s_threshold_pm1 = s.threshold_pm1.or_else(|| threshold_pm1.clone());
+                let s_rules = s.rules.or_else(|| rules.clone());
+                let s_clear_margin = s.clear_margin.or(clear_margin);
+                let s_hold_readings = s.hold_readings.or(hold_readings);
                 let s_notification_condition = s.notification_condition.or_else(|| e_mail_condition);
+                let s_interval = s.interval.or(interval);
+                let s_notify_on_failure = s.notify_on_failure.or(notify_on_failure);
+                let s_unreachable_after_failures = s.unreachable_after_failures.or(unreachable_after_failures);
+                let s_unreachable_after = s.unreachable_after.or(unreachable_after);
+                let s_max_age = s.max_age.or(max_age);
+                let s_exceedance_limit = s.exceedance_limit.or(exceedance_limit);
+                let s_max_exceedance_days = s.max_exceedance_days.or(max_exceedance_days);
+                let s_humidity_correction = s.humidity_correction.or(humidity_correction);
+                let s_suppress_above_humidity = s.suppress_above_humidity.or(suppress_above_humidity);
+                let s_plausible_ranges = s.plausible_ranges.or_else(|| plausible_ranges.clone());
+                let s_stuck_readings = s.stuck_readings.or(stuck_readings);
+                let s_notify_on_suspect = s.notify_on_suspect.or(notify_on_suspect);
+                let s_connect_timeout = s.connect_timeout.or(connect_timeout);
+                let s_read_timeout = s.read_timeout.or(read_timeout);
+                let s_retries = s.retries.or(retries);
+                let s_retry_backoff = s.retry_backoff.or(retry_backoff);
+                let s_max_redirects = s.max_redirects.or(max_redirects);
+                let s_ca_file = s.ca_file.or_else(|| ca_file.clone());
+                let s_api_uri = s.api_uri.or_else(|| api_uri.clone());
+                let s_strict = s.strict.or(strict);
+                let s_verify_certificate = s.verify_certificate.or(verify_certificate);
                 Sensor {
                     threshold_pm10: s_threshold_pm10,
                     threshold_pm2: s_threshold_pm2,
+                    threshold_pm1: s_threshold_pm1,
+                    rules: s_rules,
+                    clear_margin: s_clear_margin,
+                    hold_readings: s_hold_readings,
                     notification_condition: s_notification_condition,
+                    interval: s_interval,
+                    notify_on_failure: s_notify_on_failure,
+                    unreachable_after_failures: s_unreachable_after_failures,
+                    unreachable_after: s_unreachable_after,
+                    max_age: s_max_age,
+                    exceedance_limit: s_exceedance_limit,
+                    max_exceedance_days: s_max_exceedance_days,
+                    humidity_correction: s_humidity_correction,
+                    suppress_above_humidity: s_suppress_above_humidity,
+                    plausible_ranges: s_plausible_ranges,
+                    stuck_readings: s_stuck_readings,
+                    notify_on_suspect: s_notify_on_suspect,
+                    connect_timeout: s_connect_timeout,
+                    read_timeout: s_read_timeout,
+                    retries: s_retries,
+                    retry_backoff: s_retry_backoff,
+                    max_redirects: s_max_redirects,
+                    ca_file: s_ca_file,
+                    api_uri: s_api_uri,
+                    strict: s_strict,
+                    verify_certificate: s_verify_certificate,
                     ..s
                 }
             })
             .collect();
 
+        let groups = config
+            .groups
+            .into_iter()
+            .map(|g| {
+                let g_aggregation = g.aggregation.or(Some(Aggregation::Mean));
+                let g_threshold_pm10 = g.threshold_pm10.or_else(|| threshold_pm10.clone());
+                let g_threshold_pm2 = g.threshold_pm2.or_else(|| threshold_pm2.clone());
+                let g_threshold_pm1 = g.threshold_pm1.or_else(|| threshold_pm1.clone());
+                let g_rules = g.rules.or_else(|| rules.clone());
+                let g_clear_margin = g.clear_margin.or(clear_margin);
+                let g_hold_readings = g.hold_readings.or(hold_readings);
+                let g_notification_condition = g.notification_condition.or(e_mail_condition);
+                let g_max_age = g.max_age.or(max_age);
+                let g_humidity_correction = g.humidity_correction.or(humidity_correction);
+                let g_suppress_above_humidity = g.suppress_above_humidity.or(suppress_above_humidity);
+                Group {
+                    aggregation: g_aggregation,
+                    threshold_pm10: g_threshold_pm10,
+                    threshold_pm2: g_threshold_pm2,
+                    threshold_pm1: g_threshold_pm1,
+                    rules: g_rules,
+                    clear_margin: g_clear_margin,
+                    hold_readings: g_hold_readings,
+                    notification_condition: g_notification_condition,
+                    max_age: g_max_age,
+                    humidity_correction: g_humidity_correction,
+                    suppress_above_humidity: g_suppress_above_humidity,
+                    ..g
+                }
+            })
+            .collect();
+
         let defaults = Defaults {
             threshold_pm10: threshold_pm10,
             threshold_pm2: threshold_pm2,
+            threshold_pm1: threshold_pm1,
+            rules: rules,
+            clear_margin: clear_margin,
+            hold_readings: hold_readings,
             notification_condition: e_mail_condition,
+            notify_on_failure: notify_on_failure,
+            verify_certificate: verify_certificate,
+            ca_file: ca_file,
+            unreachable_after_failures: unreachable_after_failures,
+            unreachable_after: unreachable_after,
+            max_age: max_age,
+            exceedance_limit: exceedance_limit,
+            max_exceedance_days: max_exceedance_days,
+            humidity_correction: humidity_correction,
+            suppress_above_humidity: suppress_above_humidity,
+            plausible_ranges: plausible_ranges,
+            stuck_readings: stuck_readings,
+            notify_on_suspect: notify_on_suspect,
+        };
+        let general = General {
+            interval: interval,
+            connect_timeout: connect_timeout,
+            read_timeout: read_timeout,
+            retries: retries,
+            retry_backoff: retry_backoff,
+            max_redirects: max_redirects,
+            strict: strict,
+            ..config.general
         };
         Config {
+            general: general,
             defaults: defaults,
             sensors: sensors,
+            groups: groups,
             ..config
         }
     }
@@ -159,6 +910,7 @@ impl Config {
 #[cfg(test)]
 mod test {
     use super::*;
+    use check::Severity;
 
     #[test]
     pub fn config_from_min_str_okay() -> () {
@@ -176,16 +928,56 @@ data_uri = "http://feinstaub/data.json"
         let config = Config::parse_toml(config_str).unwrap();
 
         assert_eq!(config.sensors.len(), 1);
+        assert_eq!(config.general.interval.unwrap(), DEFAULT_INTERVAL);
+        assert_eq!(config.sensors[0].interval.unwrap(), DEFAULT_INTERVAL);
+        assert_eq!(config.sensors[0].connect_timeout.unwrap(), DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(config.sensors[0].read_timeout.unwrap(), DEFAULT_READ_TIMEOUT);
+        assert_eq!(config.sensors[0].retries.unwrap(), DEFAULT_RETRIES);
+        assert_eq!(config.sensors[0].retry_backoff.unwrap(), DEFAULT_RETRY_BACKOFF);
+        assert_eq!(config.sensors[0].max_redirects.unwrap(), DEFAULT_MAX_REDIRECTS);
+        assert!(config.sensors[0].ca_file.is_none());
+        assert_eq!(config.sensors[0].parsed_data_uri, Some("http://feinstaub/data.json".parse().unwrap()));
+        assert_eq!(config.sensors[0].verify_certificate.unwrap(), true);
+        assert!(config.sensors[0].threshold_pm1.is_none());
+        assert_eq!(config.sensors[0].strict.unwrap(), false);
+        assert_eq!(config.sensors[0].notify_on_failure.unwrap(), false);
+        assert!(config.sensors[0].unreachable_after_failures.is_none());
+        assert!(config.sensors[0].unreachable_after.is_none());
+        assert_eq!(config.sensors[0].api_uri, Some(DEFAULT_API_URI.to_string()));
     }
 
     #[test]
     pub fn config_from_max_str_okay() -> () {
         let config_str = r#"[general]
 state_dir = '/var/lib/luftpost'
+interval = 600
+connect_timeout = 5
+read_timeout = 20
+retries = 2
+retry_backoff = 3
+strict = true
+history_retention = "1y"
 
 [defaults]
 threshold_pm10 = 10.0
 threshold_pm2 = 10.0
+threshold_pm1 = 10.0
+notify_on_failure = true
+ca_file = '/etc/luftpost/ca.pem'
+unreachable_after_failures = 3
+unreachable_after = 3600
+max_age = 900
+exceedance_limit = 40.0
+max_exceedance_days = 35
+clear_margin = 5.0
+hold_readings = 2
+suppress_above_humidity = 95.0
+[[defaults.rules]]
+value = "temperature"
+below = -5.0
+[defaults.humidity_correction]
+model = "KappaKoehler"
+kappa = 0.4
 [defaults.notification_condition]
 condition = 'OnChange'
 
@@ -213,13 +1005,23 @@ data_uri = "http://feinstaub/data.json"
 
 [[sensors]]
 name = "Max"
-id = "12345678"
+id = "87654321"
 ui_uri = "http://feinstaub"
 data_uri = "http://feinstaub/data.json"
-threshold_pm10 = 20.0
+threshold_pm10 = { mean = "24h", above = 50.0 }
 threshold_pm2 = 20.0
-e_mail_addr = "another_test@example.com"
-e_mail_subject = "Feinstaubalarm"
+threshold_pm1 = 20.0
+interval = 60
+notify_on_failure = false
+unreachable_after_failures = 5
+read_timeout = 60
+retries = 5
+verify_certificate = false
+[[sensors.rules]]
+name = "weak wifi"
+value = "signal"
+below = -80.0
+severity = "Warning"
 [sensors.notification_condition]
 condition = 'ThresholdExceeded'
 "#;
@@ -227,10 +1029,13 @@ condition = 'ThresholdExceeded'
         let config = Config::parse_toml(config_str).unwrap();
 
         assert_eq!(config.general.state_dir.unwrap(), "/var/lib/luftpost");
+        assert_eq!(config.general.history_retention.unwrap(), "1y");
+        assert_eq!(config.general.interval.unwrap(), 600);
 
-        assert_eq!(config.defaults.threshold_pm10.unwrap(), 10.0);
-        assert_eq!(config.defaults.threshold_pm2.unwrap(), 10.0);
+        assert_eq!(config.defaults.threshold_pm10.unwrap(), Threshold::Value(10.0));
+        assert_eq!(config.defaults.threshold_pm2.unwrap(), Threshold::Value(10.0));
         assert_eq!(config.defaults.notification_condition.unwrap(), NotificationCondition::OnChange);
+        assert_eq!(config.defaults.notify_on_failure.unwrap(), true);
 
         assert!(config.smtp.is_some());
         let smtp = config.smtp.unwrap();
@@ -247,17 +1052,52 @@ condition = 'ThresholdExceeded'
         );
         assert!(smtp.text_template.contains("{{ sensor.name }}"));
         assert!(smtp.html_template.contains("{{ sensor.name }}"));
+        assert!(smtp.failure_subject.contains("{{ sensor.name }}"));
+        assert!(smtp.failure_template.contains("{{ error }}"));
 
         assert_eq!(config.sensors.len(), 2);
         let s1 = &config.sensors[0];
-        assert_eq!(s1.threshold_pm10.unwrap(), 10.0);
-        assert_eq!(s1.threshold_pm2.unwrap(), 10.0);
+        assert_eq!(s1.threshold_pm10, Some(Threshold::Value(10.0)));
+        assert_eq!(s1.threshold_pm2, Some(Threshold::Value(10.0)));
+        assert_eq!(s1.threshold_pm1, Some(Threshold::Value(10.0)));
         assert_eq!(s1.notification_condition.unwrap(), NotificationCondition::OnChange);
+        assert_eq!(s1.interval.unwrap(), 600);
+        assert_eq!(s1.notify_on_failure.unwrap(), true);
+        assert_eq!(s1.unreachable_after_failures.unwrap(), 3);
+        assert_eq!(s1.unreachable_after.unwrap(), 3600);
+        assert_eq!(s1.max_age.unwrap(), 900);
+        assert_eq!(s1.clear_margin.unwrap(), 5.0);
+        assert_eq!(s1.exceedance_limit.unwrap(), 40.0);
+        assert_eq!(s1.max_exceedance_days.unwrap(), 35);
+        assert_eq!(s1.hold_readings.unwrap(), 2);
+        assert_eq!(s1.humidity_correction, Some(HumidityCorrection::KappaKoehler { kappa: 0.4 }));
+        assert_eq!(s1.suppress_above_humidity, Some(95.0));
+        assert_eq!(s1.rules, Some(vec![Rule::new("temperature", None, Some(-5.0))]));
+        assert_eq!(s1.connect_timeout.unwrap(), 5);
+        assert_eq!(s1.read_timeout.unwrap(), 20);
+        assert_eq!(s1.retries.unwrap(), 2);
+        assert_eq!(s1.retry_backoff.unwrap(), 3);
+        assert_eq!(s1.ca_file.as_ref().unwrap(), "/etc/luftpost/ca.pem");
+        assert_eq!(s1.verify_certificate.unwrap(), true);
+        assert_eq!(s1.strict.unwrap(), true);
 
         let s2 = &config.sensors[1];
-        assert_eq!(s2.threshold_pm10.unwrap(), 20.0);
-        assert_eq!(s2.threshold_pm2.unwrap(), 20.0);
+        assert_eq!(s2.threshold_pm10, Some(Threshold::Mean { mean: "24h".to_string(), above: 50.0 }));
+        assert_eq!(s2.threshold_pm2, Some(Threshold::Value(20.0)));
+        assert_eq!(s2.threshold_pm1, Some(Threshold::Value(20.0)));
+        assert_eq!(s2.rules.as_ref().unwrap()[0].name, Some("weak wifi".to_string()));
+        assert_eq!(s2.rules.as_ref().unwrap()[0].severity, Some(Severity::Warning));
         assert_eq!(s2.notification_condition.unwrap(), NotificationCondition::ThresholdExceeded);
+        assert_eq!(s2.interval.unwrap(), 60);
+        assert_eq!(s2.notify_on_failure.unwrap(), false);
+        assert_eq!(s2.unreachable_after_failures.unwrap(), 5);
+        assert_eq!(s2.unreachable_after.unwrap(), 3600);
+        assert_eq!(s2.connect_timeout.unwrap(), 5);
+        assert_eq!(s2.read_timeout.unwrap(), 60);
+        assert_eq!(s2.retries.unwrap(), 5);
+        assert_eq!(s2.retry_backoff.unwrap(), 3);
+        assert_eq!(s2.ca_file.as_ref().unwrap(), "/etc/luftpost/ca.pem");
+        assert_eq!(s2.verify_certificate.unwrap(), false);
     }
 
     #[test]
@@ -273,12 +1113,644 @@ condition = 'ThresholdExceeded'
     }
 
     #[test]
-    pub fn from_file() -> () {
-        let path = Path::new("tests/luftpost.example.conf");
+    pub fn config_from_str_invalid_data_uri() -> () {
+        let config_str = r#"[general]
 
-        let config = Config::from_file(&path).unwrap();
+[defaults]
 
-        assert_eq!(config.sensors.len(), 1);
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+
+[[sensors]]
+name = "Typo"
+id = "87654321"
+ui_uri = "http://feinstaub"
+data_uri = "http//feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidSensorUri(ref sensor, ref key, _, _), _)) => {
+                assert_eq!(sensor, "sensors[1] ('Typo')");
+                assert_eq!(key, "data_uri");
+            }
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_unsupported_scheme() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "ftp://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidSensorUri(_, ref key, _, _), _)) => assert_eq!(key, "ui_uri"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_api_sensor_okay() -> () {
+        let config_str = r#"[general]
+api_uri = "http://localhost:8080/v1/sensor/"
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+api_id = "12345"
+"#;
+
+        let config = Config::parse_toml(config_str).unwrap();
+
+        assert!(config.sensors[0].data_uri.is_none());
+        assert_eq!(config.sensors[0].api_id, Some("12345".to_string()));
+        assert_eq!(config.sensors[0].api_uri, Some("http://localhost:8080/v1/sensor/".to_string()));
+    }
+
+    #[test]
+    pub fn config_from_str_sources_okay() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Http"
+id = "1"
+ui_uri = "http://feinstaub"
+source = { kind = "Http", uri = "http://feinstaub/data.json" }
+
+[[sensors]]
+name = "Api"
+id = "2"
+ui_uri = "http://feinstaub"
+source = { kind = "Api", id = "12345" }
+
+[[sensors]]
+name = "File"
+id = "3"
+ui_uri = "http://feinstaub"
+source = { kind = "File", path = "/tmp/data.json" }
+
+[[sensors]]
+name = "Stdin"
+id = "4"
+ui_uri = "http://feinstaub"
+source = { kind = "Stdin" }
+"#;
+
+        let config = Config::parse_toml(config_str).unwrap();
+
+        assert_eq!(config.sensors[0].source(), Some(Source::Http { uri: "http://feinstaub/data.json".to_string() }));
+        assert_eq!(config.sensors[1].source(), Some(Source::Api { id: "12345".to_string(), uri: None }));
+        assert_eq!(config.sensors[2].source(), Some(Source::File { path: "/tmp/data.json".to_string() }));
+        assert_eq!(config.sensors[3].source(), Some(Source::Stdin));
+    }
+
+    #[test]
+    pub fn config_from_str_multiple_sources() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+source = { kind = "Stdin" }
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].source"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_no_data_source() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].source"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_unknown_key() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+e_mail_addr = "another_test@example.com"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::CouldNotParse(ref e), _)) => assert!(e.to_string().contains("e_mail_addr")),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_duplicate_sensor_id() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub 1"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+
+[[sensors]]
+name = "Feinstaub 2"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::DuplicateValue(ref path, _), _)) => assert_eq!(path, "sensors[1].id"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_negative_threshold() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+threshold_pm2 = -1.0
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].threshold_pm2"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_invalid_rule() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+[[sensors.rules]]
+value = "temprature"
+below = -5.0
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].rules[0].value"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_invalid_aqi_category() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+[[sensors.rules]]
+value = "caqi"
+category = "Unhealthy"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].rules[0].category"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_rate_without_window() -> () {
+        let config_str = r#"[general]
+state_dir = "/var/lib/luftpost"
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+[[sensors.rules]]
+value = "pm10"
+rise = 20.0
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].rules[0].rise"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_groups() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+threshold_pm10 = 40.0
+max_age = 900
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+
+[[sensors]]
+name = "Feinstaub 2"
+id = "23456789"
+ui_uri = "http://feinstaub2"
+data_uri = "http://feinstaub2/data.json"
+
+[[groups]]
+name = "Neighbourhood"
+id = "neighbourhood"
+sensors = ["12345678", "23456789"]
+aggregation = { method = "AtLeast", count = 2 }
+suppress_above_humidity = 90.0
+"#;
+
+        let config = Config::parse_toml(config_str).unwrap();
+
+        let g = &config.groups[0];
+        assert_eq!(g.aggregation, Some(Aggregation::AtLeast { count: 2 }));
+        assert_eq!(g.threshold_pm10, Some(Threshold::Value(40.0)));
+        assert_eq!(g.notification_condition, Some(NotificationCondition::ThresholdExceeded));
+        assert_eq!(g.max_age, Some(900));
+        assert_eq!(g.suppress_above_humidity, Some(90.0));
+        let sensor = g.sensor();
+        assert_eq!(sensor.max_age, Some(900));
+        assert_eq!(sensor.suppress_above_humidity, Some(90.0));
+
+        let config = Config::parse_toml(&config_str.replace(r#""23456789"]"#, r#""34567890"]"#));
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "groups[0].sensors[1]"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_plausibility() -> () {
+        let config_str = r#"[general]
+state_dir = "/tmp"
+
+[defaults]
+stuck_readings = 5
+[[defaults.plausible_ranges]]
+value = "pm10"
+min = 0.0
+max = 999.0
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str).unwrap();
+
+        let s = &config.sensors[0];
+        assert_eq!(s.plausible_ranges.as_ref().unwrap()[0].max, Some(999.0));
+        assert_eq!(s.stuck_readings, Some(5));
+        assert_eq!(s.notify_on_suspect, Some(false));
+
+        let config = Config::parse_toml(&config_str.replace("min = 0.0", "min = 1000.0"));
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.plausible_ranges[0].min"),
+            _ => assert!(false),
+        }
+
+        let config = Config::parse_toml(&config_str.replace("state_dir = \"/tmp\"", ""));
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.stuck_readings"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_colocations() -> () {
+        let config_str = r#"[general]
+state_dir = "/tmp"
+history_retention = "7d"
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+
+[[sensors]]
+name = "Feinstaub 2"
+id = "23456789"
+ui_uri = "http://feinstaub2"
+data_uri = "http://feinstaub2/data.json"
+
+[[colocations]]
+name = "Balcony"
+id = "balcony"
+sensors = ["12345678", "23456789"]
+max_ratio = 1.5
+window = "24h"
+"#;
+
+        let config = Config::parse_toml(config_str).unwrap();
+
+        let c = &config.colocations[0];
+        assert_eq!(c.max_ratio, Some(1.5));
+        assert_eq!(c.window, Some("24h".to_string()));
+
+        let config = Config::parse_toml(&config_str.replace("max_ratio = 1.5", ""));
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "colocations[0].max_ratio"),
+            _ => assert!(false),
+        }
+
+        let config = Config::parse_toml(&config_str.replace("history_retention = \"7d\"", ""));
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "colocations[0].window"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_unordered_severities() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+[[defaults.rules]]
+value = "pm10"
+above = 50.0
+severity = "Alert"
+[[defaults.rules]]
+value = "pm10"
+above = 40.0
+severity = "Critical"
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.rules[1].above"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn parse_duration_okay() -> () {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("24h"), Some(Duration::hours(24)));
+        assert_eq!(parse_duration("1y"), Some(Duration::days(365)));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("24"), None);
+        assert_eq!(parse_duration("24 hours"), None);
+    }
+
+    #[test]
+    pub fn config_from_str_mean_without_state_dir() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+threshold_pm10 = { mean = "24h", above = 50.0 }
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.threshold_pm10.mean"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_mean_window_too_long() -> () {
+        let config_str = r#"[general]
+state_dir = "/tmp"
+
+[defaults]
+threshold_pm10 = { mean = "1y", above = 40.0 }
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.threshold_pm10.mean"),
+            _ => assert!(false),
+        }
+        assert!(Config::parse_toml(&config_str.replace("1y", "7d")).is_ok());
+        let config_str = config_str.replace("state_dir = \"/tmp\"", "state_dir = \"/tmp\"\nhistory_retention = \"1y\"");
+        assert!(Config::parse_toml(&config_str).is_ok());
+    }
+
+    #[test]
+    pub fn config_from_str_retries() -> () {
+        let config_str = r#"[general]
+retries = 6
+
+[defaults]
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+retry_backoff = 0
+"#;
+
+        match Config::parse_toml(config_str) {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "general.retries"),
+            _ => assert!(false),
+        }
+        match Config::parse_toml(&config_str.replace("retries = 6", "retries = 5")) {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].retry_backoff"),
+            _ => assert!(false),
+        }
+        match Config::parse_toml(&config_str.replace("retries = 6", "retries = 5").replace("retry_backoff = 0", "retry_backoff = 61")) {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "sensors[0].retry_backoff"),
+            _ => assert!(false),
+        }
+        assert!(Config::parse_toml(&config_str.replace("retries = 6", "retries = 5").replace("retry_backoff = 0", "retry_backoff = 60")).is_ok());
+    }
+
+    #[test]
+    pub fn config_from_str_on_change_without_state_dir() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+[defaults.notification_condition]
+condition = 'OnChange'
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "defaults.notification_condition"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_auth_mechanism_without_credentials() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[smtp]
+sender = "test@example.com"
+subject = "PM alarm"
+receiver = "test@example.com"
+server = "localhost"
+auth_mechanism = "Plain"
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidValue(ref path, _), _)) => assert_eq!(path, "smtp.auth_mechanism"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn config_from_str_invalid_template() -> () {
+        let config_str = r#"[general]
+
+[defaults]
+
+[smtp]
+sender = "test@example.com"
+subject = "PM alarm"
+receiver = "test@example.com"
+server = "localhost"
+text_template = "{{#if has_violations}}exceeded"
+
+[[sensors]]
+name = "Feinstaub"
+id = "12345678"
+ui_uri = "http://feinstaub"
+data_uri = "http://feinstaub/data.json"
+"#;
+
+        let config = Config::parse_toml(config_str);
+
+        match config {
+            Err(Error(ErrorKind::InvalidTemplate(ref path, _), _)) => assert_eq!(path, "smtp.text_template"),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    pub fn from_file() -> () {
+        let path = Path::new("tests/luftpost.example.conf");
+
+        let config = Config::from_file(&path).unwrap();
+
+        assert_eq!(config.sensors.len(), 3);
     }
 
     #[test]
@@ -0,0 +1,195 @@
+use futures::{future, Future, IntoFuture, Poll};
+use hyper::Uri;
+use hyper::client::{Connect, Service};
+use native_tls;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::{TlsConnector, TlsStream};
+
+pub struct TimeoutConnector<C> {
+    connector: C,
+    timeout: Duration,
+    handle: Handle,
+}
+
+impl<C: Connect> TimeoutConnector<C> {
+    pub fn new(connector: C, timeout: Duration, handle: &Handle) -> TimeoutConnector<C> {
+        TimeoutConnector {
+            connector: connector,
+            timeout: timeout,
+            handle: handle.clone(),
+        }
+    }
+}
+
+impl<C: Connect> Service for TimeoutConnector<C> {
+    type Request = Uri;
+    type Response = C::Output;
+    type Error = io::Error;
+    type Future = Box<Future<Item = C::Output, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let connecting = self.connector.connect(uri);
+        let timeout = Timeout::new(self.timeout, &self.handle)
+            .into_future()
+            .flatten()
+            .and_then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")));
+
+        let connection = connecting
+            .select(timeout)
+            .map(|(connection, _)| connection)
+            .map_err(|(e, _)| e);
+        Box::new(connection)
+    }
+}
+
+pub struct HttpsConnector<C> {
+    connector: C,
+    tls: TlsConnector,
+}
+
+impl<C: Connect> HttpsConnector<C> {
+    pub fn new(connector: C, tls: native_tls::TlsConnector) -> HttpsConnector<C> {
+        HttpsConnector {
+            connector: connector,
+            tls: tls.into(),
+        }
+    }
+}
+
+impl<C: Connect> Service for HttpsConnector<C> {
+    type Request = Uri;
+    type Response = MaybeHttpsStream<C::Output>;
+    type Error = io::Error;
+    type Future = Box<Future<Item = MaybeHttpsStream<C::Output>, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let is_https = uri.scheme() == Some("https");
+        let host = match uri.host() {
+            Some(host) => host.to_string(),
+            None => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, "missing host in uri"))),
+        };
+        let connecting = self.connector.connect(uri);
+
+        if is_https {
+            let tls = self.tls.clone();
+            let connection = connecting.and_then(move |tcp| {
+                tls.connect(&host, tcp)
+                    .map(MaybeHttpsStream::Https)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            });
+            Box::new(connection)
+        } else {
+            Box::new(connecting.map(MaybeHttpsStream::Http))
+        }
+    }
+}
+
+pub enum MaybeHttpsStream<S> {
+    Http(S),
+    Https(TlsStream<S>),
+}
+
+impl<S: Read + Write> Read for MaybeHttpsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.read(buf),
+            MaybeHttpsStream::Https(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for MaybeHttpsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.write(buf),
+            MaybeHttpsStream::Https(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.flush(),
+            MaybeHttpsStream::Https(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for MaybeHttpsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for MaybeHttpsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.shutdown(),
+            MaybeHttpsStream::Https(ref mut s) => s.shutdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use hyper::client::HttpConnector;
+    use std::net::TcpListener;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    struct NeverConnector;
+
+    impl Service for NeverConnector {
+        type Request = Uri;
+        type Response = TcpStream;
+        type Error = io::Error;
+        type Future = future::Empty<TcpStream, io::Error>;
+
+        fn call(&self, _: Uri) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    #[test]
+    fn timeout_connector_times_out() -> () {
+        let mut core = Core::new().unwrap();
+        let connector = TimeoutConnector::new(NeverConnector, Duration::from_millis(10), &core.handle());
+
+        let res = core.run(connector.call("http://feinstaub".parse().unwrap()));
+
+        match res {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn https_connector_http_okay() -> () {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut core = Core::new().unwrap();
+        let http = HttpConnector::new(1, &core.handle());
+        let connector = HttpsConnector::new(http, native_tls::TlsConnector::builder().build().unwrap());
+
+        let uri = format!("http://{}/data.json", listener.local_addr().unwrap()).parse().unwrap();
+        let res = core.run(connector.call(uri));
+
+        match res {
+            Ok(MaybeHttpsStream::Http(_)) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn https_connector_missing_host() -> () {
+        let mut core = Core::new().unwrap();
+        let http = HttpConnector::new(1, &core.handle());
+        let connector = HttpsConnector::new(http, native_tls::TlsConnector::builder().build().unwrap());
+
+        let res = core.run(connector.call("/data.json".parse().unwrap()));
+
+        match res {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => assert!(true),
+            _ => assert!(false),
+        }
+    }
+}
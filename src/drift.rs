@@ -0,0 +1,282 @@
+use check::CheckedMeasurement;
+use config::parse_duration;
+use history::{Error, History, Result};
+use sensor::{Sensor, SensorId};
+use state::SensorState;
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+pub const DEFAULT_DRIFT_VALUE: &str = "pm10";
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Colocation {
+    pub name: String,
+    pub id: SensorId,
+    pub sensors: Vec<SensorId>,
+    pub value: Option<String>,
+    pub max_ratio: Option<f32>,
+    pub max_difference: Option<f32>,
+    pub min_value: Option<f32>,
+    pub window: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Reading {
+    pub name: String,
+    pub id: SensorId,
+    pub value: f32,
+    pub mean: Option<f32>,
+    pub samples: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Drift {
+    pub colocation: Colocation,
+    pub value: String,
+    pub readings: Vec<Reading>,
+    pub ratio: Option<f32>,
+    pub difference: f32,
+    pub mean_ratio: Option<f32>,
+    pub mean_difference: Option<f32>,
+    pub drifting: bool,
+}
+
+// Co-located sensors are compared on their current readings and, with a time window, on their means from the history;
+// sensors not read in this run are compared on their last reading and stale or suspect readings are not compared.
+// The invalid lines skipped in the history are returned along with the drift.
+pub fn check_drift(
+    colocation: &Colocation,
+    sensors: &[Sensor],
+    checked_measurements: &[CheckedMeasurement],
+    sensor_states: &HashMap<SensorId, SensorState>,
+    history: Option<&History>,
+    now: DateTime<Utc>,
+) -> Result<(Option<Drift>, Vec<Error>)> {
+    let value = colocation.value.clone().unwrap_or_else(|| DEFAULT_DRIFT_VALUE.to_string());
+    let window = colocation.window.as_ref().and_then(|w| parse_duration(w));
+    let mut readings = Vec::new();
+    let mut skipped = Vec::new();
+    for id in &colocation.sensors {
+        let last_reading;
+        let cm = match checked_measurements.iter().find(|cm| &cm.measurement.sensor.id == id) {
+            Some(cm) => cm,
+            None => {
+                last_reading = match (sensors.iter().find(|s| &s.id == id), sensor_states.get(id)) {
+                    (Some(sensor), Some(state)) => state.last_checked_measurement(sensor, None, now),
+                    _ => None,
+                };
+                match last_reading {
+                    Some(ref cm) => cm,
+                    None => return Ok((None, skipped)),
+                }
+            }
+        };
+        if cm.stale || cm.suspect {
+            return Ok((None, skipped));
+        }
+        let current = match cm.measurement.data_values.iter().filter_map(|v| v.select(&value)).next() {
+            Some(current) => current,
+            None => return Ok((None, skipped)),
+        };
+        let values = match (history, window) {
+            (Some(history), Some(window)) => {
+                let (values, sensor_skipped) = history.query(id, &value, now - window, now)?;
+                skipped.extend(sensor_skipped);
+                values.into_iter().map(|(_, v)| v).collect()
+            }
+            _ => Vec::new(),
+        };
+        let mean = if values.is_empty() { None } else { Some(values.iter().sum::<f32>() / values.len() as f32) };
+        readings.push(Reading {
+            name: cm.measurement.sensor.name.clone(),
+            id: id.clone(),
+            value: current,
+            mean: mean,
+            samples: values.len(),
+        });
+    }
+
+    let (ratio, difference, drifting) = compare(colocation, &readings.iter().map(|r| r.value).collect::<Vec<_>>());
+    // The history may not hold all sensors yet, e.g., right after enabling it
+    let means = readings.iter().filter_map(|r| r.mean).collect::<Vec<_>>();
+    let (mean_ratio, mean_difference, mean_drifting) = if window.is_some() && means.len() == readings.len() {
+        let (ratio, difference, drifting) = compare(colocation, &means);
+        (ratio, Some(difference), drifting)
+    } else {
+        (None, None, false)
+    };
+
+    Ok((Some(Drift {
+        colocation: colocation.clone(),
+        value: value,
+        readings: readings,
+        ratio: ratio,
+        difference: difference,
+        mean_ratio: mean_ratio,
+        mean_difference: mean_difference,
+        drifting: drifting || mean_drifting,
+    }), skipped))
+}
+
+// Ratios of low concentrations are dominated by noise, so they are only compared from 'min_value' on; a sensor reading
+// zero while another one reads at least 'min_value' exceeds any ratio
+fn compare(colocation: &Colocation, values: &[f32]) -> (Option<f32>, f32, bool) {
+    let lowest = values.iter().cloned().fold(::std::f32::MAX, f32::min);
+    let highest = values.iter().cloned().fold(::std::f32::MIN, f32::max);
+    let difference = highest - lowest;
+    let compared = highest > 0.0 && highest >= colocation.min_value.unwrap_or(0.0);
+    let ratio = if compared && lowest > 0.0 { Some(highest / lowest) } else { None };
+    let ratio_drifting = match (ratio, colocation.max_ratio) {
+        (Some(ratio), Some(max_ratio)) => ratio > max_ratio,
+        (None, Some(_)) => compared,
+        _ => false,
+    };
+    let difference_drifting = colocation.max_difference.map(|max| difference > max).unwrap_or(false);
+
+    (ratio, difference, ratio_drifting || difference_drifting)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use check::check_measurement;
+    use measurement::{Measurement, Value};
+    use sensor::Sensor;
+
+    use chrono::Duration;
+    use mktemp::Temp;
+
+    fn measurement(id: &str, pm10: f32, timestamp: DateTime<Utc>) -> Measurement {
+        Measurement {
+            sensor: Sensor::new(id, id, "http://localhost", "http://localhost"),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(pm10)],
+            timestamp: Some(timestamp),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: timestamp,
+            age: None,
+        }
+    }
+
+    fn colocation(window: Option<&str>) -> Colocation {
+        Colocation {
+            name: "Balcony".to_string(),
+            id: "balcony".to_string(),
+            sensors: vec!["1".to_string(), "2".to_string()],
+            value: None,
+            max_ratio: Some(2.0),
+            max_difference: None,
+            min_value: Some(10.0),
+            window: window.map(|w| w.to_string()),
+        }
+    }
+
+    #[test]
+    fn check_drift_okay() -> () {
+        let now = Utc::now();
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 12.0, now)),
+            check_measurement(measurement("2", 40.0, now)),
+        ];
+
+        let res = check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), None, now).unwrap().0.unwrap();
+        assert!(res.drifting);
+        assert_eq!(res.difference, 28.0);
+
+        // Both readings are below 'min_value', so the ratio is not compared
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 2.0, now)),
+            check_measurement(measurement("2", 8.0, now)),
+        ];
+        let res = check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), None, now).unwrap().0.unwrap();
+        assert!(!res.drifting);
+        assert_eq!(res.ratio, None);
+
+        assert!(check_drift(&colocation(None), &[], &checked_measurements[..1], &HashMap::new(), None, now).unwrap().0.is_none());
+
+        // Suspect readings are not compared
+        let mut suspect = check_measurement(measurement("2", 999.9, now));
+        suspect.suspect = true;
+        let checked_measurements = vec![check_measurement(measurement("1", 12.0, now)), suspect];
+        assert!(check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), None, now).unwrap().0.is_none());
+    }
+
+    #[test]
+    fn check_drift_history() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(&state_dir);
+        let now = Utc::now();
+        for &(hours, pm10_1, pm10_2) in &[(3, 20.0, 60.0), (2, 20.0, 60.0), (1, 20.0, 20.0)] {
+            history.append(&measurement("1", pm10_1, now - Duration::hours(hours))).unwrap();
+            history.append(&measurement("2", pm10_2, now - Duration::hours(hours))).unwrap();
+        }
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 20.0, now - Duration::hours(1))),
+            check_measurement(measurement("2", 20.0, now - Duration::hours(1))),
+        ];
+
+        let res = check_drift(&colocation(Some("6h")), &[], &checked_measurements, &HashMap::new(), Some(&history), now).unwrap().0.unwrap();
+
+        assert_eq!(res.readings[1].value, 20.0);
+        assert_eq!(res.readings[1].mean, Some(140.0 / 3.0));
+        assert_eq!(res.readings[1].samples, 3);
+        assert_eq!(res.ratio, Some(1.0));
+        assert_eq!(res.mean_difference, Some(140.0 / 3.0 - 20.0));
+        assert!(res.drifting);
+        assert!(!check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), Some(&history), now).unwrap().0.unwrap().drifting);
+
+        // The current readings are compared as well as the means
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 12.0, now)),
+            check_measurement(measurement("2", 40.0, now)),
+        ];
+        let res = check_drift(&colocation(Some("1h")), &[], &checked_measurements, &HashMap::new(), Some(&history), now).unwrap().0.unwrap();
+        assert_eq!(res.mean_ratio, Some(1.0));
+        assert!(res.drifting);
+    }
+
+    #[test]
+    fn check_drift_zero() -> () {
+        let now = Utc::now();
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 0.0, now)),
+            check_measurement(measurement("2", 40.0, now)),
+        ];
+
+        let res = check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), None, now).unwrap().0.unwrap();
+        assert_eq!(res.ratio, None);
+        assert!(res.drifting);
+
+        let checked_measurements = vec![
+            check_measurement(measurement("1", 0.0, now)),
+            check_measurement(measurement("2", 0.0, now)),
+        ];
+        let res = check_drift(&colocation(None), &[], &checked_measurements, &HashMap::new(), None, now).unwrap().0.unwrap();
+        assert!(!res.drifting);
+    }
+
+    #[test]
+    fn check_drift_last_readings() -> () {
+        let now = Utc::now();
+        let sensors = vec![Sensor::new("1", "1", "http://localhost", "http://localhost")];
+        let first_run = check_measurement(measurement("1", 12.0, now - Duration::minutes(5)));
+        let mut state = SensorState::new(&"1".to_string());
+        state.record_last_reading(&first_run.measurement, &first_run.raw_data_values);
+        let mut sensor_states = HashMap::new();
+        sensor_states.insert("1".to_string(), state);
+        let checked_measurements = vec![check_measurement(measurement("2", 40.0, now))];
+
+        let res = check_drift(&colocation(None), &sensors, &checked_measurements, &sensor_states, None, now).unwrap().0.unwrap();
+        assert_eq!(res.readings[0].value, 12.0);
+        assert!(res.drifting);
+
+        // Last readings older than two polling intervals are left out
+        let later = now + Duration::hours(1);
+        assert!(check_drift(&colocation(None), &sensors, &checked_measurements, &sensor_states, None, later).unwrap().0.is_none());
+    }
+}
@@ -0,0 +1,376 @@
+use check::{CheckedMeasurement, Direction, HumidityCorrection, Rule, Severity, Threshold, Violation, check_measurement_with_state};
+use config::NotificationCondition;
+use measurement::{Measurement, Value};
+use sensor::{Sensor, SensorId};
+use state::SensorState;
+
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "method")]
+#[derive(PartialEq, Clone, Copy)]
+pub enum Aggregation {
+    Mean,
+    Median,
+    Max,
+    AtLeast { count: usize },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub name: String,
+    pub id: SensorId,
+    pub sensors: Vec<SensorId>,
+    pub aggregation: Option<Aggregation>,
+    pub threshold_pm10: Option<Threshold>,
+    pub threshold_pm2: Option<Threshold>,
+    pub threshold_pm1: Option<Threshold>,
+    pub rules: Option<Vec<Rule>>,
+    pub clear_margin: Option<f32>,
+    pub hold_readings: Option<u32>,
+    pub notification_condition: Option<NotificationCondition>,
+    pub max_age: Option<u64>,
+    pub humidity_correction: Option<HumidityCorrection>,
+    pub suppress_above_humidity: Option<f32>,
+}
+
+impl Group {
+    // A group is checked like a sensor with the group's thresholds, rules, notification condition, and humidity settings
+    pub fn sensor(&self) -> Sensor {
+        Sensor {
+            data_uri: None,
+            threshold_pm10: self.threshold_pm10.clone(),
+            threshold_pm2: self.threshold_pm2.clone(),
+            threshold_pm1: self.threshold_pm1.clone(),
+            rules: self.rules.clone(),
+            clear_margin: self.clear_margin,
+            hold_readings: self.hold_readings,
+            notification_condition: self.notification_condition,
+            max_age: self.max_age,
+            humidity_correction: self.humidity_correction,
+            suppress_above_humidity: self.suppress_above_humidity,
+            ..Sensor::new(&self.name[..], &self.id[..], "", "")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Member {
+    pub name: String,
+    pub id: SensorId,
+    pub data_values: Vec<Value>,
+    pub pm10: Option<f32>,
+    pub pm2: Option<f32>,
+    pub pm1: Option<f32>,
+    pub has_violations: bool,
+    pub severity: Option<Severity>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CheckedGroup {
+    pub group: Group,
+    pub checked_measurement: CheckedMeasurement,
+    pub members: Vec<Member>,
+}
+
+// Members not read in this run are aggregated on their last reading unless it is older than the group's 'max_age'
+// or, without one, than two polling intervals of the member; their level is the member's alarm state
+pub fn last_readings(
+    group: &Group,
+    sensors: &[Sensor],
+    checked_measurements: &[CheckedMeasurement],
+    sensor_states: &HashMap<SensorId, SensorState>,
+    now: DateTime<Utc>,
+) -> Vec<CheckedMeasurement> {
+    sensors
+        .iter()
+        .filter(|s| group.sensors.contains(&s.id))
+        .filter(|s| !checked_measurements.iter().any(|cm| cm.measurement.sensor.id == s.id))
+        .filter_map(|s| sensor_states.get(&s.id).and_then(|state| state.last_checked_measurement(s, group.max_age, now).map(|cm| (state, cm))))
+        .map(|(state, mut checked_measurement)| {
+            checked_measurement.severity = state.alarm_state.severity();
+            checked_measurement.has_violations = checked_measurement.severity.is_some();
+            checked_measurement
+        })
+        .collect()
+}
+
+// Groups are checked on the non-stale, non-suspect measurements of their members read in the same run and on the last
+// readings of the other members; the group's humidity correction applies to the aggregated raw values
+pub fn check_group(
+    group: &Group,
+    checked_measurements: &[CheckedMeasurement],
+    last_readings: &[CheckedMeasurement],
+    group_state: Option<&SensorState>,
+) -> Option<CheckedGroup> {
+    let members = checked_measurements
+        .iter()
+        .chain(last_readings)
+        .filter(|cm| !cm.stale && !cm.suspect && group.sensors.contains(&cm.measurement.sensor.id))
+        .collect::<Vec<_>>();
+    if members.is_empty() {
+        return None;
+    }
+
+    let aggregation = group.aggregation.unwrap_or(Aggregation::Mean);
+    let measurement = aggregate(group.sensor(), &members, aggregation);
+    let mut checked_measurement = check_measurement_with_state(measurement, group_state);
+    if let Aggregation::AtLeast { count } = aggregation {
+        let violations = check_at_least(group, &members, group_state, count);
+        checked_measurement.has_violations = !violations.is_empty();
+        checked_measurement.severity = violations.iter().map(|v| v.severity).max();
+        checked_measurement.violations = violations;
+    }
+
+    let members = members
+        .iter()
+        .map(|cm| Member {
+            name: cm.measurement.sensor.name.clone(),
+            id: cm.measurement.sensor.id.clone(),
+            data_values: cm.measurement.data_values.clone(),
+            pm10: cm.pm10,
+            pm2: cm.pm2,
+            pm1: cm.pm1,
+            has_violations: cm.has_violations,
+            severity: cm.severity,
+        })
+        .collect();
+
+    Some(CheckedGroup {
+        group: group.clone(),
+        checked_measurement: checked_measurement,
+        members: members,
+    })
+}
+
+// Values of the same value type are aggregated; 'AtLeast' shows the median
+fn aggregate(sensor: Sensor, members: &[&CheckedMeasurement], aggregation: Aggregation) -> Measurement {
+    let mut value_types: Vec<&str> = Vec::new();
+    for cm in members {
+        for v in cm.raw_data_values.iter().filter(|v| v.value().is_some()) {
+            if !value_types.contains(&v.value_type()) {
+                value_types.push(v.value_type());
+            }
+        }
+    }
+    let data_values = value_types
+        .iter()
+        .map(|value_type| {
+            let mut values = members
+                .iter()
+                .filter_map(|cm| cm.raw_data_values.iter().find(|v| v.value_type() == *value_type))
+                .filter_map(|v| v.value())
+                .collect::<Vec<_>>();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            // Unwrap is safe because each value type has at least one value
+            let value = match aggregation {
+                Aggregation::Mean => values.iter().sum::<f32>() / values.len() as f32,
+                Aggregation::Max => *values.last().unwrap(),
+                Aggregation::Median | Aggregation::AtLeast { .. } => median(&values),
+            };
+            Value::from((*value_type, value))
+        })
+        .collect();
+    let timestamp = members.iter().map(|cm| cm.measurement.timestamp.unwrap_or(cm.measurement.fetched_at)).max();
+    // Unwrap is safe because there is at least one member
+    let fetched_at = members.iter().map(|cm| cm.measurement.fetched_at).max().unwrap();
+
+    Measurement {
+        sensor: sensor,
+        software_version: String::new(),
+        data_values: data_values,
+        timestamp: timestamp,
+        location: None,
+        warnings: Vec::new(),
+        fetched_at: fetched_at,
+        age: None,
+    }
+}
+
+fn median(sorted: &[f32]) -> f32 {
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+// A rule is violated if at least 'count' members violate it; the violation reports the count-th most extreme member
+fn check_at_least(group: &Group, members: &[&CheckedMeasurement], group_state: Option<&SensorState>, count: usize) -> Vec<Violation> {
+    let sensor = group.sensor();
+    let mut by_rule: Vec<(String, Vec<Violation>)> = Vec::new();
+    for cm in members {
+        let measurement = Measurement {
+            sensor: sensor.clone(),
+            data_values: cm.raw_data_values.clone(),
+            software_version: String::new(),
+            timestamp: cm.measurement.timestamp,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: cm.measurement.fetched_at,
+            age: None,
+        };
+        // Each member counts once per rule
+        let mut rules = Vec::new();
+        for violation in check_measurement_with_state(measurement, group_state).violations {
+            if rules.contains(&violation.rule_id) {
+                continue;
+            }
+            rules.push(violation.rule_id.clone());
+            match by_rule.iter_mut().find(|&&mut (ref rule, _)| rule == &violation.rule_id) {
+                Some(&mut (_, ref mut violations)) => violations.push(violation),
+                None => by_rule.push((violation.rule_id.clone(), vec![violation])),
+            }
+        }
+    }
+
+    by_rule
+        .into_iter()
+        .filter(|&(_, ref violations)| violations.len() >= count)
+        .map(|(_, mut violations)| {
+            violations.sort_by(|a, b| {
+                let ordering = b.measured.partial_cmp(&a.measured).unwrap_or(Ordering::Equal);
+                if a.direction == Direction::Below { ordering.reverse() } else { ordering }
+            });
+            violations.swap_remove(count - 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use check::check_measurement;
+    use config::DEFAULT_INTERVAL;
+
+    use chrono::{Duration, Utc};
+
+    fn checked_measurement(id: &str, pm10: f32) -> CheckedMeasurement {
+        let sensor = Sensor::new(id, id, "http://localhost", "http://localhost");
+        check_measurement(Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(pm10), Value::TEMPERATURE(20.0)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        })
+    }
+
+    fn group(aggregation: Aggregation) -> Group {
+        Group {
+            name: "Neighbourhood".to_string(),
+            id: "neighbourhood".to_string(),
+            sensors: vec!["1".to_string(), "2".to_string(), "3".to_string()],
+            aggregation: Some(aggregation),
+            threshold_pm10: Some(Threshold::Value(50.0)),
+            threshold_pm2: None,
+            threshold_pm1: None,
+            rules: None,
+            clear_margin: None,
+            hold_readings: None,
+            notification_condition: None,
+            max_age: None,
+            humidity_correction: None,
+            suppress_above_humidity: None,
+        }
+    }
+
+    #[test]
+    fn check_group_aggregations() -> () {
+        let checked_measurements = vec![
+            checked_measurement("1", 10.0),
+            checked_measurement("2", 20.0),
+            checked_measurement("3", 120.0),
+            checked_measurement("4", 500.0),
+        ];
+
+        let mean = check_group(&group(Aggregation::Mean), &checked_measurements, &[], None).unwrap();
+        assert_eq!(mean.checked_measurement.pm10, Some(50.0));
+        assert!(!mean.checked_measurement.has_violations);
+        assert_eq!(mean.members.len(), 3);
+
+        let median = check_group(&group(Aggregation::Median), &checked_measurements, &[], None).unwrap();
+        assert_eq!(median.checked_measurement.pm10, Some(20.0));
+
+        let max = check_group(&group(Aggregation::Max), &checked_measurements, &[], None).unwrap();
+        assert_eq!(max.checked_measurement.pm10, Some(120.0));
+        assert_eq!(max.checked_measurement.violations.len(), 1);
+        assert_eq!(max.checked_measurement.measurement.sensor.name, "Neighbourhood");
+    }
+
+    #[test]
+    fn check_group_at_least() -> () {
+        let checked_measurements = vec![
+            checked_measurement("1", 60.0),
+            checked_measurement("2", 20.0),
+            checked_measurement("3", 120.0),
+        ];
+
+        let res = check_group(&group(Aggregation::AtLeast { count: 2 }), &checked_measurements, &[], None).unwrap();
+        assert_eq!(res.checked_measurement.violations.len(), 1);
+        assert_eq!(res.checked_measurement.violations[0].measured, 60.0);
+
+        let res = check_group(&group(Aggregation::AtLeast { count: 3 }), &checked_measurements, &[], None).unwrap();
+        assert!(!res.checked_measurement.has_violations);
+        assert_eq!(res.checked_measurement.severity, None);
+    }
+
+    #[test]
+    fn check_group_last_readings() -> () {
+        let sensors = ["1", "2", "3"].iter().map(|id| Sensor::new(*id, *id, "http://localhost", "http://localhost")).collect::<Vec<_>>();
+        let group = group(Aggregation::Mean);
+        let mut sensor_states = HashMap::new();
+
+        // Sensor 1 is polled first, sensor 2 in a later run
+        let first_run = vec![checked_measurement("1", 80.0)];
+        let mut state = SensorState::new(&"1".to_string());
+        state.record_last_reading(&first_run[0].measurement, &first_run[0].raw_data_values);
+        sensor_states.insert("1".to_string(), state);
+
+        let second_run = vec![checked_measurement("2", 40.0)];
+        let last_readings = last_readings(&group, &sensors, &second_run, &sensor_states, Utc::now());
+        assert_eq!(last_readings.len(), 1);
+        let res = check_group(&group, &second_run, &last_readings, None).unwrap();
+        assert_eq!(res.members.len(), 2);
+        assert_eq!(res.checked_measurement.pm10, Some(60.0));
+        assert!(res.checked_measurement.has_violations);
+
+        // Last readings older than two polling intervals are left out
+        let later = Utc::now() + Duration::seconds(2 * DEFAULT_INTERVAL as i64 + 60);
+        assert!(super::last_readings(&group, &sensors, &second_run, &sensor_states, later).is_empty());
+        let group = Group { max_age: Some(3600), ..group };
+        assert_eq!(super::last_readings(&group, &sensors, &second_run, &sensor_states, later).len(), 1);
+    }
+
+    #[test]
+    fn check_group_humidity_correction() -> () {
+        let sensor = Sensor {
+            humidity_correction: Some(HumidityCorrection::KappaKoehler { kappa: 0.5 }),
+            ..Sensor::new("1", "1", "http://localhost", "http://localhost")
+        };
+        let checked_measurements = vec![check_measurement(Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(80.0), Value::HUMIDITY(90.0)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        })];
+
+        // The group corrects the aggregated raw values once
+        let group = Group { humidity_correction: Some(HumidityCorrection::KappaKoehler { kappa: 0.5 }), ..group(Aggregation::Mean) };
+        let res = check_group(&group, &checked_measurements, &[], None).unwrap();
+        assert_eq!(res.checked_measurement.pm10, checked_measurements[0].pm10);
+        assert!(res.checked_measurement.humidity_corrected);
+    }
+}
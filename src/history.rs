@@ -0,0 +1,435 @@
+use measurement::{Measurement, Value};
+use sensor::SensorId;
+use state::DailyMean;
+
+use chrono::{DateTime, Utc};
+use serde_json;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// The history is read backwards in chunks of this size
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+error_chain! {
+    errors {
+        FailedToAppend(sensor: String, state_dir: String) {
+            description("failed to append to history")
+            display("failed to append measurement of sensor '{}' to history in state directory '{}'", sensor, state_dir)
+        }
+        FailedToRead(sensor: String, state_dir: String) {
+            description("failed to read history")
+            display("failed to read history of sensor '{}' from state directory '{}'", sensor, state_dir)
+        }
+        FailedToPrune(sensor: String, state_dir: String) {
+            description("failed to prune history")
+            display("failed to prune history of sensor '{}' in state directory '{}'", sensor, state_dir)
+        }
+        InvalidLine(offset: u64, history: String) {
+            description("invalid line in history")
+            display("skipped invalid line at byte {} of history '{}'", offset, history)
+        }
+    }
+    foreign_links {
+        Io(::std::io::Error);
+        JsonError(::serde_json::Error);
+    }
+}
+
+// One line per measurement, e.g., '{"timestamp":"2018-06-01T12:00:00Z","values":{"SDS_P1":7.87,"SDS_P2":3.17}}'
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub values: BTreeMap<String, f32>,
+}
+
+impl Entry {
+    pub fn from_measurement(measurement: &Measurement) -> Entry {
+        let values = measurement.data_values
+            .iter()
+            .filter_map(|v| v.value().map(|value| (v.value_type().to_string(), value)))
+            .collect();
+
+        Entry {
+            timestamp: measurement.timestamp.unwrap_or(measurement.fetched_at),
+            values: values,
+        }
+    }
+
+    pub fn select(&self, value_type: &str) -> Option<f32> {
+        self.values
+            .iter()
+            .filter_map(|(name, value)| Value::from((&name[..], *value)).select(value_type))
+            .next()
+    }
+}
+
+// Daily means of complete days by sensor and value type for the time range they have been requested for
+type DailyMeansCache = HashMap<(SensorId, String), (DateTime<Utc>, DateTime<Utc>, Vec<DailyMean>)>;
+
+pub struct History {
+    state_dir: PathBuf,
+    daily_means: RefCell<DailyMeansCache>,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(state_dir: P) -> History {
+        History {
+            state_dir: state_dir.as_ref().to_path_buf(),
+            daily_means: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn append(&self, measurement: &Measurement) -> Result<()> {
+        let sensor_id = &measurement.sensor.id;
+        let fp = self.filepath(sensor_id);
+        append_to_file(&Entry::from_measurement(measurement), fp).chain_err(|| ErrorKind::FailedToAppend(sensor_id.clone(), self.state_dir_str()))
+    }
+
+    // Entries from 'from' inclusive up to 'to' exclusive; the same reading read more than once is only returned once.
+    // The invalid lines skipped are returned along with the entries.
+    pub fn entries(&self, sensor_id: &SensorId, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(Vec<Entry>, Vec<Error>)> {
+        let fp = self.filepath(sensor_id);
+        let (entries, skipped) = read_from_file(&fp, Some(from)).chain_err(|| ErrorKind::FailedToRead(sensor_id.clone(), self.state_dir_str()))?;
+        let mut res: Vec<Entry> = Vec::new();
+        for e in entries.into_iter().filter(|e| e.timestamp < to) {
+            if res.last().map(|last| last.timestamp == e.timestamp).unwrap_or(false) {
+                continue;
+            }
+            res.push(e);
+        }
+
+        Ok((res, skipped))
+    }
+
+    // 'value_type' is a value type like 'SDS_P1' or 'BME280_TEMPERATURE', or one of 'pm10', 'pm2', and 'pm1'
+    pub fn query(&self, sensor_id: &SensorId, value_type: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(Vec<(DateTime<Utc>, f32)>, Vec<Error>)> {
+        let (entries, skipped) = self.entries(sensor_id, from, to)?;
+        let values = entries
+            .into_iter()
+            .filter_map(|e| e.select(value_type).map(|v| (e.timestamp, v)))
+            .collect();
+
+        Ok((values, skipped))
+    }
+
+    // Daily means are based on UTC days. A day is complete once there are entries of a later day, so the daily means of
+    // complete days are kept and only the entries from the last day on are read again when asked for the same range.
+    pub fn daily_means(&self, sensor_id: &SensorId, value_type: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<(Vec<DailyMean>, Vec<Error>)> {
+        let key = (sensor_id.clone(), value_type.to_string());
+        let mut daily_means = match self.daily_means.borrow().get(&key) {
+            Some(&(cached_from, cached_to, ref daily_means)) if cached_from == from && cached_to == to => daily_means.clone(),
+            _ => Vec::new(),
+        };
+        let read_from = daily_means
+            .last()
+            .map(|d| DateTime::from_utc(d.date.succ().and_hms(0, 0, 0), Utc))
+            .unwrap_or(from);
+        let (values, skipped) = self.query(sensor_id, value_type, read_from, to)?;
+        for (timestamp, value) in values {
+            let date = timestamp.naive_utc().date();
+            if let Some(daily_mean) = daily_means.iter_mut().find(|d| d.date == date) {
+                daily_mean.sum += value;
+                daily_mean.count += 1;
+                daily_mean.last_reading = daily_mean.last_reading.max(timestamp);
+                continue;
+            }
+            daily_means.push(DailyMean {
+                date: date,
+                sum: value,
+                count: 1,
+                last_reading: timestamp,
+            });
+        }
+        let complete = daily_means.len().saturating_sub(1);
+        self.daily_means.borrow_mut().insert(key, (from, to, daily_means[..complete].to_vec()));
+
+        Ok((daily_means, skipped))
+    }
+
+    // Rewrites the history without the entries before 'before' and the invalid lines; returns the number of removed
+    // entries and the invalid lines skipped
+    pub fn prune(&self, sensor_id: &SensorId, before: DateTime<Utc>) -> Result<(usize, Vec<Error>)> {
+        let fp = self.filepath(sensor_id);
+        let res = prune_file(&fp, before).chain_err(|| ErrorKind::FailedToPrune(sensor_id.clone(), self.state_dir_str()))?;
+        // The daily means of the pruned days must not be counted any longer
+        self.daily_means.borrow_mut().retain(|&(ref id, _), _| id != sensor_id);
+
+        Ok(res)
+    }
+
+    // Returns the timestamp of the oldest entry without reading the whole history
+    pub fn oldest(&self, sensor_id: &SensorId) -> Result<Option<DateTime<Utc>>> {
+        let fp = self.filepath(sensor_id);
+        read_first_from_file(fp)
+            .map(|e| e.map(|e| e.timestamp))
+            .chain_err(|| ErrorKind::FailedToRead(sensor_id.clone(), self.state_dir_str()))
+    }
+
+    fn filepath(&self, sensor_id: &SensorId) -> PathBuf {
+        let mut pb = self.state_dir.clone();
+        let filename = format!("{}.history", sensor_id);
+        pb.push(filename);
+
+        pb
+    }
+
+    fn state_dir_str(&self) -> String {
+        self.state_dir.to_string_lossy().to_string()
+    }
+}
+
+fn append_to_file<P: AsRef<Path>>(entry: &Entry, file_path: P) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(file_path)?;
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+// The history is appended in chronological order, so only its end is read up to the first entry before 'from', or the
+// whole history without 'from'. A single corrupt line, e.g., from an interrupted write, is skipped instead of failing
+// the whole history.
+fn read_from_file(file_path: &Path, from: Option<DateTime<Utc>>) -> Result<(Vec<Entry>, Vec<Error>)> {
+    let mut file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    // The beginning of the first line of the chunk read before which starts in the chunk read next
+    let mut rest = Vec::new();
+    'chunks: while pos > 0 {
+        let len = cmp::min(CHUNK_SIZE, pos);
+        pos -= len;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut chunk = vec![0; len as usize];
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&rest);
+
+        let mut end = chunk.len();
+        loop {
+            let start = match chunk[..end].iter().rposition(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None if pos == 0 => 0,
+                None => break,
+            };
+            let line = &chunk[start..end];
+            if !line.iter().all(|b| b.is_ascii_whitespace()) {
+                match serde_json::from_slice::<Entry>(line) {
+                    Ok(ref entry) if from.map(|from| entry.timestamp < from).unwrap_or(false) => break 'chunks,
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => {
+                        let offset = pos + start as u64;
+                        skipped.push(Error::with_chain(e, ErrorKind::InvalidLine(offset, file_path.to_string_lossy().to_string())));
+                    }
+                }
+            }
+            if start == 0 {
+                break;
+            }
+            end = start - 1;
+        }
+        rest = chunk[..end].to_vec();
+    }
+    entries.reverse();
+    skipped.reverse();
+
+    Ok((entries, skipped))
+}
+
+fn read_first_from_file<P: AsRef<Path>>(file_path: P) -> Result<Option<Entry>> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    for line in BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            return Ok(Some(entry));
+        }
+    }
+
+    Ok(None)
+}
+
+// The pruned history is written to a temporary file first which then replaces the history
+fn prune_file(file_path: &Path, before: DateTime<Utc>) -> Result<(usize, Vec<Error>)> {
+    let (entries, skipped) = read_from_file(file_path, None)?;
+    let len = entries.len();
+    let retained = entries.into_iter().filter(|e| e.timestamp >= before).collect::<Vec<_>>();
+    if retained.len() == len && skipped.is_empty() {
+        return Ok((0, skipped));
+    }
+
+    let tmp_path = file_path.with_extension("history.tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for e in &retained {
+            serde_json::to_writer(&mut writer, e)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, file_path)?;
+
+    Ok((len - retained.len(), skipped))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{Duration, NaiveDate, TimeZone};
+    use mktemp::Temp;
+    use sensor::Sensor;
+
+    fn measurement(timestamp: DateTime<Utc>, pm10: f32) -> Measurement {
+        Measurement {
+            sensor: Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(pm10), Value::TEMPERATURE(18.9), Value::UNKNOWN("new".to_string(), "x".to_string())],
+            timestamp: Some(timestamp),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: timestamp,
+            age: None,
+        }
+    }
+
+    #[test]
+    pub fn append_and_query_okay() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor_id = "123456789".to_string();
+        let now = Utc::now();
+
+        history.append(&measurement(now - Duration::hours(2), 10.0)).unwrap();
+        history.append(&measurement(now - Duration::hours(1), 20.0)).unwrap();
+        history.append(&measurement(now - Duration::hours(1), 20.0)).unwrap();
+        history.append(&measurement(now, 30.0)).unwrap();
+
+        let (res, _) = history.query(&sensor_id, "pm10", now - Duration::hours(1), now).unwrap();
+        assert_eq!(res, vec![(now - Duration::hours(1), 20.0)]);
+        let (res, _) = history.query(&sensor_id, "temperature", now - Duration::hours(3), now + Duration::hours(1)).unwrap();
+        assert_eq!(res.len(), 3);
+        let (entries, _) = history.entries(&sensor_id, now, now + Duration::hours(1)).unwrap();
+        assert_eq!(entries[0].values.len(), 2);
+    }
+
+    #[test]
+    pub fn query_without_history_okay() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let now = Utc::now();
+
+        let res = history.query(&"123456789".to_string(), "pm10", now - Duration::hours(1), now);
+
+        let (res, skipped) = res.unwrap();
+        assert_eq!(res, Vec::new());
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    pub fn query_skips_invalid_lines() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor_id = "123456789".to_string();
+        let now = Utc::now();
+        history.append(&measurement(now - Duration::hours(2), 10.0)).unwrap();
+        let offset = fs::metadata(history.filepath(&sensor_id)).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(history.filepath(&sensor_id)).unwrap();
+            file.write_all(b"{\"timestamp\":\"2018-06-01T12:\n").unwrap();
+        }
+        history.append(&measurement(now - Duration::hours(1), 20.0)).unwrap();
+
+        let (res, skipped) = history.query(&sensor_id, "pm10", now - Duration::hours(3), now).unwrap();
+
+        assert_eq!(res.len(), 2);
+        assert_eq!(skipped.len(), 1);
+        match *skipped[0].kind() {
+            ErrorKind::InvalidLine(invalid, _) => assert_eq!(invalid, offset),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        // Pruning drops the invalid line
+        assert_eq!(history.prune(&sensor_id, now - Duration::days(1)).unwrap().1.len(), 1);
+        assert!(history.query(&sensor_id, "pm10", now - Duration::hours(3), now).unwrap().1.is_empty());
+    }
+
+    #[test]
+    pub fn query_reads_the_end_only() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor_id = "123456789".to_string();
+        let now = Utc::now();
+        // More than one chunk
+        for minutes in (0..2000).rev() {
+            history.append(&measurement(now - Duration::minutes(minutes), minutes as f32)).unwrap();
+        }
+        assert!(fs::metadata(history.filepath(&sensor_id)).unwrap().len() > 2 * CHUNK_SIZE);
+
+        let (res, _) = history.query(&sensor_id, "pm10", now - Duration::minutes(1999), now + Duration::minutes(1)).unwrap();
+        assert_eq!(res.len(), 2000);
+        assert_eq!(res[0], (now - Duration::minutes(1999), 1999.0));
+        assert_eq!(res[1999], (now, 0.0));
+        let (res, _) = history.query(&sensor_id, "pm10", now - Duration::minutes(10), now).unwrap();
+        assert_eq!(res.len(), 10);
+        assert_eq!(res[0], (now - Duration::minutes(10), 10.0));
+    }
+
+    #[test]
+    pub fn daily_means_okay() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor_id = "123456789".to_string();
+        let midnight = Utc.ymd(2018, 6, 2).and_hms(0, 0, 0);
+        history.append(&measurement(midnight - Duration::hours(2), 40.0)).unwrap();
+        history.append(&measurement(midnight - Duration::hours(1), 60.0)).unwrap();
+        history.append(&measurement(midnight + Duration::hours(1), 10.0)).unwrap();
+
+        let (res, _) = history.daily_means(&sensor_id, "pm10", midnight - Duration::days(1), midnight + Duration::days(1)).unwrap();
+
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].date, NaiveDate::from_ymd(2018, 6, 1));
+        assert_eq!(res[0].mean(), 50.0);
+        assert_eq!(res[1].count, 1);
+
+        // The complete days are kept, the last day is read again
+        history.append(&measurement(midnight + Duration::hours(2), 20.0)).unwrap();
+        let (res, _) = history.daily_means(&sensor_id, "pm10", midnight - Duration::days(1), midnight + Duration::days(1)).unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].mean(), 50.0);
+        assert_eq!(res[1].mean(), 15.0);
+
+        // Pruning drops the kept days
+        history.prune(&sensor_id, midnight).unwrap();
+        let (res, _) = history.daily_means(&sensor_id, "pm10", midnight - Duration::days(1), midnight + Duration::days(1)).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].count, 2);
+    }
+
+    #[test]
+    pub fn prune_okay() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor_id = "123456789".to_string();
+        let now = Utc::now();
+        history.append(&measurement(now - Duration::days(2), 10.0)).unwrap();
+        history.append(&measurement(now - Duration::hours(1), 20.0)).unwrap();
+
+        assert_eq!(history.oldest(&sensor_id).unwrap(), Some(now - Duration::days(2)));
+        assert_eq!(history.prune(&sensor_id, now - Duration::days(1)).unwrap().0, 1);
+        assert_eq!(history.oldest(&sensor_id).unwrap(), Some(now - Duration::hours(1)));
+        assert_eq!(history.prune(&sensor_id, now - Duration::days(1)).unwrap().0, 0);
+    }
+}
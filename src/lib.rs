@@ -1,10 +1,14 @@
+extern crate chrono;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
 extern crate handlebars;
 extern crate hyper;
 extern crate lettre;
+extern crate native_tls;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_tls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -12,21 +16,34 @@ extern crate serde_json;
 extern crate tabwriter;
 extern crate toml;
 
+pub mod aqi;
 pub mod check;
 pub mod config;
+pub mod connector;
+pub mod drift;
+pub mod group;
+pub mod history;
 pub mod mail;
 pub mod measurement;
 pub mod output;
+pub mod schedule;
 pub mod sensor;
+pub mod source;
 pub mod state;
 
-pub use check::{CheckedMeasurement, check_measurement};
+pub use aqi::Index;
+pub use check::{CheckedMeasurement, HumidityCorrection, Mean, PlausibleRange, Rate, Rule, Severity, Threshold, Violation, check_measurement, check_measurement_with_state};
 pub use config::Config;
+pub use drift::{Colocation, Drift, Reading, check_drift};
+pub use group::{CheckedGroup, Group, Member, check_group, last_readings};
+pub use history::History;
 pub use mail::Mailer;
 pub use measurement::Measurement;
-pub use output::print_measurements;
-pub use sensor::{Sensor, SensorId, create_sensor_reader};
-pub use state::{AlarmState, SensorState};
+pub use output::{print_aqi, print_drifts, print_exceedances, print_failures, print_groups, print_humidity, print_means, print_measurements, print_rates, print_suspects, print_violations, print_warnings};
+pub use schedule::Schedule;
+pub use sensor::{FailedMeasurement, Sensor, SensorId};
+pub use source::{MeasurementSource, SensorClient, Source, create_sensor_reader};
+pub use state::{AlarmState, DailyMean, Exceedances, Sample, SensorState, ViolatedRule};
 
 #[cfg(test)]
 #[macro_use]
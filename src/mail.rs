@@ -1,6 +1,11 @@
 use config::Smtp;
 use handlebars::{Handlebars, RenderError, RenderContext, Helper};
-use check::CheckedMeasurement;
+use check::{CheckedMeasurement, Severity};
+use drift::Drift;
+use group::{CheckedGroup, Group, Member};
+use sensor::{FailedMeasurement, Sensor};
+use serde::Serialize;
+use state::SensorState;
 use lettre::email::{Email, EmailBuilder};
 use lettre::transport::EmailTransport;
 use lettre::transport::smtp::{SecurityLevel, SmtpTransport, SmtpTransportBuilder};
@@ -36,6 +41,10 @@ pub struct Mailer<'a> {
     pub subject: &'a str,
     pub text_template: &'a str,
     pub html_template: &'a str,
+    pub failure_subject: &'a str,
+    pub failure_template: &'a str,
+    pub maintenance_subject: &'a str,
+    pub maintenance_template: &'a str,
 }
 
 impl<'a> Mailer<'a> {
@@ -60,13 +69,79 @@ impl<'a> Mailer<'a> {
             subject: &smtp.subject,
             text_template: &smtp.text_template,
             html_template: &smtp.html_template,
+            failure_subject: &smtp.failure_subject,
+            failure_template: &smtp.failure_template,
+            maintenance_subject: &smtp.maintenance_subject,
+            maintenance_template: &smtp.maintenance_template,
         };
 
         Ok(mailer)
     }
 
-    pub fn mail_measurement(&mut self, check_measurement: &CheckedMeasurement) -> Result<()> {
-        let (subject, text, html) = create_body(check_measurement, self.subject,
+    pub fn mail_measurement(&mut self, check_measurement: &CheckedMeasurement, previous_severity: Option<Severity>) -> Result<()> {
+        let context = TemplateContext {
+            checked_measurement: Some(check_measurement),
+            sensor: &check_measurement.measurement.sensor,
+            sensor_state: None,
+            previous_severity: previous_severity,
+            escalated: check_measurement.severity > previous_severity,
+            unreachable: false,
+            recovered: false,
+            group: None,
+            members: None,
+        };
+        self.mail_context(&context)
+    }
+
+    // Group notifications use the group as sensor and additionally provide the group and its members
+    pub fn mail_group(&mut self, checked_group: &CheckedGroup, previous_severity: Option<Severity>) -> Result<()> {
+        let check_measurement = &checked_group.checked_measurement;
+        let context = TemplateContext {
+            checked_measurement: Some(check_measurement),
+            sensor: &check_measurement.measurement.sensor,
+            sensor_state: None,
+            previous_severity: previous_severity,
+            escalated: check_measurement.severity > previous_severity,
+            unreachable: false,
+            recovered: false,
+            group: Some(&checked_group.group),
+            members: Some(&checked_group.members),
+        };
+        self.mail_context(&context)
+    }
+
+    pub fn mail_recovered(&mut self, check_measurement: &CheckedMeasurement, sensor_state: &SensorState) -> Result<()> {
+        let context = TemplateContext {
+            checked_measurement: Some(check_measurement),
+            sensor: &check_measurement.measurement.sensor,
+            sensor_state: Some(sensor_state),
+            previous_severity: None,
+            escalated: false,
+            unreachable: false,
+            recovered: true,
+            group: None,
+            members: None,
+        };
+        self.mail_context(&context)
+    }
+
+    pub fn mail_unreachable(&mut self, sensor: &Sensor, sensor_state: &SensorState) -> Result<()> {
+        let context = TemplateContext {
+            checked_measurement: None,
+            sensor: sensor,
+            sensor_state: Some(sensor_state),
+            previous_severity: None,
+            escalated: false,
+            unreachable: true,
+            recovered: false,
+            group: None,
+            members: None,
+        };
+        self.mail_context(&context)
+    }
+
+    fn mail_context(&mut self, context: &TemplateContext) -> Result<()> {
+        let (subject, text, html) = create_body(context, self.subject,
                                                 self.text_template, self.html_template)?;
         let email = EmailBuilder::new()
             .to(self.to_addr)
@@ -77,6 +152,47 @@ impl<'a> Mailer<'a> {
         self.send(email)
     }
 
+    pub fn mail_failure(&mut self, failed_measurement: &FailedMeasurement) -> Result<()> {
+        let (subject, text) = create_text_body(failed_measurement, self.failure_subject, self.failure_template)?;
+        let email = EmailBuilder::new()
+            .to(self.to_addr)
+            .from(self.from_addr)
+            .subject(&subject)
+            .text(&text)
+            .build()?;
+        self.send(email)
+    }
+
+    // Maintenance notifications are about the sensors themselves, not about the air quality
+    pub fn mail_maintenance(&mut self, drift: &Drift) -> Result<()> {
+        let context = MaintenanceContext {
+            drift: Some(drift),
+            checked_measurement: None,
+            sensor: None,
+        };
+        self.send_maintenance(&context)
+    }
+
+    pub fn mail_suspect(&mut self, checked_measurement: &CheckedMeasurement) -> Result<()> {
+        let context = MaintenanceContext {
+            drift: None,
+            checked_measurement: Some(checked_measurement),
+            sensor: Some(&checked_measurement.measurement.sensor),
+        };
+        self.send_maintenance(&context)
+    }
+
+    fn send_maintenance(&mut self, context: &MaintenanceContext) -> Result<()> {
+        let (subject, text) = create_text_body(context, self.maintenance_subject, self.maintenance_template)?;
+        let email = EmailBuilder::new()
+            .to(self.to_addr)
+            .from(self.from_addr)
+            .subject(&subject)
+            .text(&text)
+            .build()?;
+        self.send(email)
+    }
+
     fn send(&mut self, email: Email) -> Result<()> {
         match self.transport {
             Transport::File(ref mut file) => file.send(email).map(|_| ()).map_err(|e| e.into()),
@@ -86,23 +202,57 @@ impl<'a> Mailer<'a> {
     }
 }
 
-fn create_body(check_measurement: &CheckedMeasurement, subject_template: &str, text_template: &str, html_template: &str) -> Result<(String, String, String)> {
+#[derive(Serialize)]
+struct TemplateContext<'a> {
+    #[serde(flatten)]
+    checked_measurement: Option<&'a CheckedMeasurement>,
+    sensor: &'a Sensor,
+    sensor_state: Option<&'a SensorState>,
+    previous_severity: Option<Severity>,
+    escalated: bool,
+    unreachable: bool,
+    recovered: bool,
+    group: Option<&'a Group>,
+    members: Option<&'a [Member]>,
+}
+
+#[derive(Serialize)]
+struct MaintenanceContext<'a> {
+    #[serde(flatten)]
+    drift: Option<&'a Drift>,
+    #[serde(flatten)]
+    checked_measurement: Option<&'a CheckedMeasurement>,
+    sensor: Option<&'a Sensor>,
+}
+
+fn create_body<T: Serialize>(context: &T, subject_template: &str, text_template: &str, html_template: &str) -> Result<(String, String, String)> {
     let mut handlebars = Handlebars::new();
     handlebars.register_helper("number", Box::new(handlebars_number_formatter));
     handlebars.register_helper("exceeds", Box::new(handlebars_number_comparision));
     handlebars.register_template_string("subject", subject_template)?;
     handlebars.register_template_string("text_template", text_template)?;
     handlebars.register_template_string("html_template", html_template)?;
-    let subject = handlebars.render("subject", check_measurement)?;
-    let text = handlebars.render("text_template", check_measurement)?;
-    let html = handlebars.render("html_template", check_measurement)?;
+    let subject = handlebars.render("subject", context)?;
+    let text = handlebars.render("text_template", context)?;
+    let html = handlebars.render("html_template", context)?;
 
     Ok((subject, text, html))
 }
 
+fn create_text_body<T: Serialize>(context: &T, subject_template: &str, text_template: &str) -> Result<(String, String)> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_helper("number", Box::new(handlebars_number_formatter));
+    handlebars.register_template_string("subject", subject_template)?;
+    handlebars.register_template_string("text_template", text_template)?;
+    let subject = handlebars.render("subject", context)?;
+    let text = handlebars.render("text_template", context)?;
+
+    Ok((subject, text))
+}
+
 fn handlebars_number_formatter(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> ::std::result::Result<(), RenderError> {
-    let param = h.param(0).unwrap();
-    let number = param.value().as_f64().unwrap();
+    let param = h.param(0).ok_or_else(|| RenderError::new("number expects a parameter"))?;
+    let number = param.value().as_f64().ok_or_else(|| RenderError::new("number expects a number"))?;
 
     let f = format!("{:.2}", number);
     rc.writer.write_all(f.as_bytes())?;
@@ -111,10 +261,10 @@ fn handlebars_number_formatter(h: &Helper, _: &Handlebars, rc: &mut RenderContex
 }
 
 fn handlebars_number_comparision(h: &Helper, _: &Handlebars, rc: &mut RenderContext) -> ::std::result::Result<(), RenderError> {
-    let param1 = h.param(0).unwrap();
-    let param2 = h.param(1).unwrap();
-    let number1 = param1.value().as_f64().unwrap();
-    let number2 = param2.value().as_f64().unwrap();
+    let param1 = h.param(0).ok_or_else(|| RenderError::new("exceeds expects two parameters"))?;
+    let param2 = h.param(1).ok_or_else(|| RenderError::new("exceeds expects two parameters"))?;
+    let number1 = param1.value().as_f64().ok_or_else(|| RenderError::new("exceeds expects numbers"))?;
+    let number2 = param2.value().as_f64().ok_or_else(|| RenderError::new("exceeds expects numbers"))?;
 
     let f = if number1 > number2 {
         ">"
@@ -132,20 +282,35 @@ fn handlebars_number_comparision(h: &Helper, _: &Handlebars, rc: &mut RenderCont
 mod test {
     use super::*;
 
+    use chrono::Utc;
     use measurement::{Measurement, Value};
     use lettre::transport::stub::StubEmailTransport;
+    use check::{Threshold, check_measurement};
+    use drift::Colocation;
+    use group::check_group;
     use sensor::Sensor;
 
+    fn mailer() -> Mailer<'static> {
+        Mailer {
+            transport: Transport::Stub(Box::new(StubEmailTransport)),
+            to_addr: "test@example.com",
+            from_addr: "sender@example.com",
+            subject: "Sensor {{ sensor.name }} exceeded thresholds",
+            text_template: "{{ sensor.name }}",
+            html_template: "{{ sensor.name }}",
+            failure_subject: "Reading sensor {{ sensor.name }} failed",
+            failure_template: "{{ error }}",
+            maintenance_subject: "Co-located sensors {{ colocation.name }} drift apart",
+            maintenance_template: "{{ difference }}",
+        }
+    }
+
     #[test]
     fn mail_measurement_okay() -> () {
         let sensor = Sensor {
-            name: "A Sensor".to_string(),
-            id: "123456789".to_string(),
-            ui_uri: "http://localhost".to_string(),
-            data_uri: "http://localhost".to_string(),
-            threshold_pm10: Some(10.0),
-            threshold_pm2: Some(2.0),
-            notification_condition: None,
+            threshold_pm10: Some(Threshold::Value(10.0)),
+            threshold_pm2: Some(Threshold::Value(2.0)),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
         };
         let mut data_values = Vec::new();
         data_values.push(Value::SDS_P1(7.87f32));
@@ -159,23 +324,146 @@ mod test {
         let measurement = Measurement {
             sensor: sensor,
             software_version: "NRZ-2017-089".to_string(),
-            data_values: data_values,
+            data_values: data_values.clone(),
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
         };
         let check_measurement = CheckedMeasurement {
             measurement: measurement,
             has_violations: false,
             violations: Vec::new(),
+            means: Vec::new(),
+            rates: Vec::new(),
+            exceedances: None,
+            severity: None,
+            us_aqi: None,
+            caqi: None,
+            pm10: Some(7.87f32),
+            pm2: Some(3.17f32),
+            pm1: None,
+            raw_pm10: Some(7.87f32),
+            raw_pm2: Some(3.17f32),
+            raw_pm1: None,
+            raw_data_values: data_values,
+            humidity: Some(49.10f32),
+            humidity_corrected: false,
+            humidity_suppressed: false,
+            stale: false,
+            suspect: false,
+            suspect_reasons: Vec::new(),
         };
         let mut mailer = Mailer {
-            transport: Transport::Stub(Box::new(StubEmailTransport)),
-            to_addr: "test@example.com",
-            from_addr: "sender@example.com",
             subject: "Sensor {{ measurement.sensor.name }} exceeded thresholds",
-            text_template: "{{ sensor.name }}",
-            html_template: "{{ sensor.name }}"
+            ..mailer()
+        };
+
+        let res = mailer.mail_measurement(&check_measurement, Some(Severity::Warning));
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn mail_group_okay() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let measurement = Measurement {
+            sensor: sensor,
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(7.87f32), Value::SDS_P2(3.17f32)],
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc::now(),
+            age: None,
+        };
+        let group = Group {
+            name: "Neighbourhood".to_string(),
+            id: "neighbourhood".to_string(),
+            sensors: vec!["123456789".to_string()],
+            aggregation: None,
+            threshold_pm10: None,
+            threshold_pm2: None,
+            threshold_pm1: None,
+            rules: None,
+            clear_margin: None,
+            hold_readings: None,
+            notification_condition: None,
+            max_age: None,
+            humidity_correction: None,
+            suppress_above_humidity: None,
+        };
+        let checked_group = check_group(&group, &[check_measurement(measurement)], &[], None).unwrap();
+        let mut mailer = Mailer {
+            subject: "Group {{ group.name }} exceeded thresholds",
+            text_template: "{{#each members as |member|}}{{ member.name }}: {{number member.pm10}}{{/each}}",
+            ..mailer()
+        };
+
+        let res = mailer.mail_group(&checked_group, None);
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn mail_failure_okay() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let failed_measurement = FailedMeasurement {
+            sensor: sensor,
+            error: "connection refused".to_string(),
+        };
+        let mut mailer = Mailer {
+            subject: "Sensor {{ measurement.sensor.name }} exceeded thresholds",
+            ..mailer()
+        };
+
+        let res = mailer.mail_failure(&failed_measurement);
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn mail_maintenance_okay() -> () {
+        let drift = Drift {
+            colocation: Colocation {
+                name: "Balcony".to_string(),
+                id: "balcony".to_string(),
+                sensors: vec!["1".to_string(), "2".to_string()],
+                value: None,
+                max_ratio: Some(1.5),
+                max_difference: None,
+                min_value: None,
+                window: None,
+            },
+            value: "pm10".to_string(),
+            readings: Vec::new(),
+            ratio: Some(2.0),
+            difference: 10.0,
+            mean_ratio: None,
+            mean_difference: None,
+            drifting: true,
+        };
+        let mut mailer = Mailer {
+            maintenance_template: "{{ value }} differs by {{number difference}}",
+            ..mailer()
+        };
+
+        let res = mailer.mail_maintenance(&drift);
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn mail_unreachable_okay() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let sensor_state = SensorState { failed_readings: 3, ..SensorState::new(&sensor.id) };
+        let mut mailer = Mailer {
+            subject: "Sensor {{ sensor.name }} {{#if unreachable}}is unreachable{{/if}}",
+            ..mailer()
         };
 
-        let res = mailer.mail_measurement(&check_measurement);
+        let res = mailer.mail_unreachable(&sensor, &sensor_state);
 
         assert!(res.is_ok());
     }
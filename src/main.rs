@@ -1,3 +1,4 @@
+extern crate chrono;
 extern crate clap;
 #[macro_use]
 extern crate error_chain;
@@ -5,27 +6,48 @@ extern crate futures;
 extern crate lettre;
 extern crate luftpost;
 extern crate tokio_core;
+extern crate tokio_signal;
 
-use clap::{Arg, App, Shell};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use clap::{Arg, App, AppSettings, ArgMatches, Shell, SubCommand};
+use futures::Future;
 use futures::future::join_all;
-use luftpost::{AlarmState, CheckedMeasurement, Config, Mailer, Measurement, Sensor, SensorId, SensorState};
-use luftpost::config::NotificationCondition;
+use luftpost::{AlarmState, CheckedMeasurement, Config, Drift, FailedMeasurement, History, Mailer, Measurement, MeasurementSource, Schedule, Sensor, SensorId, SensorState, Source};
+use luftpost::config::{self, NotificationCondition};
 use std::collections::HashMap;
 use std::io;
 use std::path::Path;
-use tokio_core::reactor::Core;
+use std::time::Instant;
+use tokio_core::reactor::{Core, Timeout};
 
 static BIN_NAME: &'static str = "luftpost";
 static VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 error_chain! {
     errors {
+        NoStateDir {
+            description("no state directory configured")
+            display("statistics require 'general.state_dir'")
+        }
+        StdinInDaemon(sensor: String) {
+            description("stdin source in daemon mode")
+            display("sensor '{}' reads from stdin, which cannot be polled by '--daemon'", sensor)
+        }
+        ExceedancesNotKept(year: i32, first: i32, last: i32) {
+            description("exceedances not kept")
+            display("exceedances of {} are not kept, only of {} to {}", year, first, last)
+        }
+        FailedToSendEmails(count: usize) {
+            description("failed to send e-mails")
+            display("failed to send {} E-Mail(s)", count)
+        }
     }
     links {
         ConfigError(luftpost::config::Error, luftpost::config::ErrorKind);
         ReadingMeasurementFailed(luftpost::sensor::Error, luftpost::sensor::ErrorKind);
         EmailError(luftpost::mail::Error, luftpost::mail::ErrorKind);
         StateError(luftpost::state::Error, luftpost::state::ErrorKind);
+        HistoryError(luftpost::history::Error, luftpost::history::ErrorKind);
     }
     foreign_links {
         IoError(std::io::Error);
@@ -66,79 +88,451 @@ fn run() -> Result<i32> {
         println!("Configuration is ok");
         return Ok(0);
     }
+    if let Some(stats_args) = cli_args.subcommand_matches("stats") {
+        return run_stats(&config, stats_args);
+    }
     let print = cli_args.is_present("print");
 
-    let sensor_states = if let Some(ref state_dir) = config.general.state_dir {
-        Option::from(load_sensor_states(&config.sensors, state_dir))
+    let mut sensor_states = if let Some(ref state_dir) = config.general.state_dir {
+        // Groups and co-locations keep their state like sensors
+        let sensors = config.sensors
+            .iter()
+            .cloned()
+            .chain(config.groups.iter().map(|g| g.sensor()))
+            .chain(config.colocations.iter().map(|c| Sensor::new(&c.name[..], &c.id[..], "", "")))
+            .collect::<Vec<_>>();
+        load_sensor_states(&sensors, state_dir)
     } else {
-        None
+        HashMap::new()
     };
 
+    let daemon = cli_args.is_present("daemon");
+    // Stdin can only be read once, so it cannot be polled repeatedly
+    if let Some(s) = config.sensors.iter().find(|s| s.source() == Some(Source::Stdin)).filter(|_| daemon) {
+        bail!(ErrorKind::StdinInDaemon(s.name.clone()));
+    }
+
     let mut core = Core::new()?;
+    let sources = config.sensors
+        .iter()
+        .map(|s| luftpost::create_sensor_reader(&core.handle(), s).map(|source| (s.id.clone(), source)))
+        .collect::<::std::result::Result<HashMap<_, _>, _>>()?;
+    let mut mailer = if let Some(ref smtp) = config.smtp {
+        Some(Mailer::create_mailer(smtp)?)
+    } else {
+        None
+    };
+    // The history is shared by all runs so that it is not read again completely per poll
+    let history = history(&config);
+
+    if daemon {
+        run_daemon(&mut core, &sources, &config, history.as_ref(), mailer.as_mut(), &mut sensor_states, print)?;
+        Ok(0)
+    } else {
+        let failed = process_sensors(
+            &mut core, &sources, &config, history.as_ref(), config.sensors.clone(), mailer.as_mut(), &mut sensor_states, print)?;
+        // Exit codes are truncated to 8 bits, so 256 failures must not become 0
+        Ok(failed.min(255) as i32)
+    }
+}
 
-    let measurements = read_measurements(&mut core, config.sensors)?;
+fn run_stats(config: &Config, args: &ArgMatches) -> Result<i32> {
+    let state_dir = config.general.state_dir.as_ref().ok_or_else(|| Error::from_kind(ErrorKind::NoStateDir))?;
+    let mut sensor_states = load_sensor_states(&config.sensors, state_dir);
+    let today = Utc::now().naive_utc().date();
+
+    if let Some(args) = args.subcommand_matches("exceedances") {
+        // Unwrap is safe because clap validates the year
+        let year = args.value_of("year").map(|y| y.parse::<i32>().unwrap()).unwrap_or_else(|| today.year());
+        let history = daily_means_history(config);
+        // The state keeps the daily means of the current and the previous year, the history as long as its retention
+        let first_kept = match history {
+            Some((_, retention)) => (Utc::now() - retention).naive_utc().date(),
+            None => NaiveDate::from_ymd(today.year() - 1, 1, 1),
+        };
+        if year < first_kept.year() || year > today.year() {
+            bail!(ErrorKind::ExceedancesNotKept(year, first_kept.year(), today.year()));
+        }
+        if year == first_kept.year() && first_kept.ordinal() > 1 {
+            eprintln!("Daily means of {} are only kept from {} on.", year, first_kept);
+        }
+        let mut exceedances = Vec::new();
+        for s in &config.sensors {
+            let state = sensor_states.entry(s.id.clone()).or_insert_with(|| SensorState::new(&s.id));
+            if let Some((ref history, _)) = history {
+                for e in state.load_daily_means(history, year)? {
+                    print_error(&e.into());
+                }
+            }
+            // Unwrap is safe because exceedance_limit is set during config parsing
+            let limit = s.exceedance_limit.unwrap();
+            exceedances.push((s, state.exceedances(year, limit, today)));
+        }
+        luftpost::print_exceedances(exceedances.as_slice());
+    }
+
+    Ok(0)
+}
+
+fn run_daemon(
+    core: &mut Core,
+    sources: &HashMap<SensorId, Box<MeasurementSource>>,
+    config: &Config,
+    history: Option<&(History, chrono::Duration)>,
+    mut mailer: Option<&mut Mailer>,
+    sensor_states: &mut HashMap<SensorId, SensorState>,
+    print: bool,
+) -> Result<()> {
+    let shutdown = shutdown_signal().shared();
+    let mut schedule = Schedule::new();
+
+    loop {
+        let now = Instant::now();
+        let due_sensors = schedule.due_sensors(&config.sensors, now);
+
+        if !due_sensors.is_empty() {
+            let res = process_sensors(core, sources, config, history, due_sensors, mailer.as_mut().map(|m| &mut **m), sensor_states, print);
+            match res {
+                Ok(failed) if failed > 0 => eprintln!("Reading {} sensor(s) failed.", failed),
+                Ok(_) => {},
+                Err(err) => {
+                    use error_chain::ChainedError;
+                    eprintln!("{}", err.display_chain());
+                }
+            }
+        }
+
+        let next_poll = schedule.next_poll(now);
+        let sleep = Timeout::new_at(next_poll, &core.handle())?.map(|_| false);
+        let signal = shutdown.clone()
+            .map(|_| true)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
+        let stop = core.run(sleep.select(signal))
+            .map(|(stop, _)| stop)
+            .map_err(|(e, _)| e)?;
+        if stop {
+            if print {
+                println!("Shutting down.");
+            }
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shutdown_signal() -> Box<Future<Item = (), Error = io::Error>> {
+    use futures::Stream;
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    let sigint = Signal::new(SIGINT).flatten_stream();
+    let sigterm = Signal::new(SIGTERM).flatten_stream();
+    let signal = sigint.select(sigterm)
+        .into_future()
+        .map(|_| ())
+        .map_err(|(e, _)| e);
+
+    Box::new(signal)
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> Box<Future<Item = (), Error = io::Error>> {
+    use futures::Stream;
+
+    let signal = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(e, _)| e);
+
+    Box::new(signal)
+}
+
+fn process_sensors(
+    core: &mut Core,
+    sources: &HashMap<SensorId, Box<MeasurementSource>>,
+    config: &Config,
+    history: Option<&(History, chrono::Duration)>,
+    sensors: Vec<Sensor>,
+    mailer: Option<&mut Mailer>,
+    sensor_states: &mut HashMap<SensorId, SensorState>,
+    print: bool,
+) -> Result<usize> {
+    let (measurements, failures): (Vec<_>, Vec<_>) = read_measurements(core, sources, sensors)?
+        .into_iter()
+        .partition(|res| res.is_ok());
+    let measurements = measurements.into_iter().flat_map(|res| res.ok()).collect::<Vec<_>>();
+    let failures = failures.into_iter().flat_map(|res| res.err()).collect::<Vec<_>>();
     if print {
         println!("Measurements collected:");
         luftpost::print_measurements(measurements.iter().map(|m| m).collect::<Vec<_>>().as_slice());
+        if measurements.iter().any(|m| !m.warnings.is_empty()) {
+            println!("Warnings:");
+            luftpost::print_warnings(measurements.iter().map(|m| m).collect::<Vec<_>>().as_slice());
+        }
+        if !failures.is_empty() {
+            println!("Failed to read measurements:");
+            luftpost::print_failures(failures.iter().map(|f| f).collect::<Vec<_>>().as_slice());
+        }
     }
+    load_history(&measurements, history, sensor_states);
     let checked_measurements = measurements
         .into_iter()
-        .map(luftpost::check_measurement)
+        .map(|m| {
+            let sensor_state = sensor_states.get(&m.sensor.id);
+            luftpost::check_measurement_with_state(m, sensor_state)
+        })
+        .collect::<Vec<_>>();
+    let now = Utc::now();
+    // In daemon mode, only the sensors due are read, so groups fall back to the last readings of the other members
+    let checked_groups = config.groups
+        .iter()
+        .filter_map(|g| {
+            let last_readings = luftpost::last_readings(g, &config.sensors, &checked_measurements, sensor_states, now);
+            luftpost::check_group(g, &checked_measurements, &last_readings, sensor_states.get(&g.id))
+        })
         .collect::<Vec<_>>();
     if print {
+        let means = checked_measurements.iter().filter(|cm| !cm.means.is_empty()).collect::<Vec<_>>();
+        if !means.is_empty() {
+            println!("Means:");
+            luftpost::print_means(means.as_slice());
+        }
+        let rates = checked_measurements.iter().filter(|cm| !cm.rates.is_empty()).collect::<Vec<_>>();
+        if !rates.is_empty() {
+            println!("Rates of change:");
+            luftpost::print_rates(rates.as_slice());
+        }
+        let humidity = checked_measurements
+            .iter()
+            .filter(|cm| cm.humidity_corrected || cm.humidity_suppressed)
+            .collect::<Vec<_>>();
+        if !humidity.is_empty() {
+            println!("Humidity corrections:");
+            luftpost::print_humidity(humidity.as_slice());
+        }
+        let indices = checked_measurements.iter().filter(|cm| cm.us_aqi.is_some() || cm.caqi.is_some()).collect::<Vec<_>>();
+        if !indices.is_empty() {
+            println!("Air quality:");
+            luftpost::print_aqi(indices.as_slice());
+        }
         println!("Measurements exceeding thresholds:");
-        let violations = checked_measurements.iter().filter(|cm| cm.has_violations).map(|cm| &cm.measurement).collect::<Vec<_>>();
-        luftpost::print_measurements(violations.as_slice())
+        let violations = checked_measurements.iter().filter(|cm| cm.has_violations).collect::<Vec<_>>();
+        luftpost::print_violations(violations.as_slice());
+        let stale = checked_measurements.iter().filter(|cm| cm.stale).map(|cm| &cm.measurement).collect::<Vec<_>>();
+        if !stale.is_empty() {
+            println!("Stale measurements not checked:");
+            luftpost::print_measurements(stale.as_slice());
+        }
+        let suspect = checked_measurements.iter().filter(|cm| cm.suspect).collect::<Vec<_>>();
+        if !suspect.is_empty() {
+            println!("Suspect measurements not checked:");
+            luftpost::print_suspects(suspect.as_slice());
+        }
+        if !checked_groups.is_empty() {
+            println!("Groups:");
+            luftpost::print_groups(checked_groups.iter().collect::<Vec<_>>().as_slice());
+        }
     }
 
+    let previous_alarm_states = checked_measurements
+        .iter()
+        .chain(checked_groups.iter().map(|cg| &cg.checked_measurement))
+        .map(|cm| &cm.measurement.sensor.id)
+        .filter_map(|id| sensor_states.get(id).map(|s| (id.clone(), s.alarm_state)))
+        .collect::<HashMap<_, _>>();
+    let suspects = record_suspects(&checked_measurements, sensor_states);
+    let recovered = update_sensor_states(&checked_measurements, sensor_states, now);
+    update_sensor_states(checked_groups.iter().map(|cg| &cg.checked_measurement), sensor_states, now);
+    let unreachable = record_failures(&failures, sensor_states, now);
+
+    let notifications = checked_measurements
+        .iter()
+        .filter(|cm| !cm.stale && !cm.suspect)
+        .filter(|cm| notify(cm, &previous_alarm_states, sensor_states))
+        .map(|cm| (cm, previous_alarm_states.get(&cm.measurement.sensor.id).and_then(|s| s.severity())))
+        .collect::<Vec<_>>();
+    let group_notifications = checked_groups
+        .iter()
+        .filter(|cg| notify(&cg.checked_measurement, &previous_alarm_states, sensor_states))
+        .map(|cg| (cg, previous_alarm_states.get(&cg.group.id).and_then(|s| s.severity())))
+        .collect::<Vec<_>>();
     if let Some(ref state_dir) = config.general.state_dir {
-        save_sensor_states(&checked_measurements, state_dir)?;
+        let sensor_ids = checked_measurements
+            .iter()
+            .chain(checked_groups.iter().map(|cg| &cg.checked_measurement))
+            .map(|cm| &cm.measurement.sensor.id)
+            .chain(failures.iter().map(|f| &f.sensor.id))
+            .collect::<Vec<_>>();
+        save_sensor_states(&sensor_ids, sensor_states, state_dir)?;
+    }
+    // Failing to record or to read the history must not prevent the notifications
+    if let Some(&(ref history, retention)) = history {
+        if let Err(e) = record_history(&checked_measurements, history, retention, now) {
+            print_error(&e.into());
+        }
+    }
+    // Drifts are checked after recording the history so that the means include the current readings
+    let history = history.map(|&(ref history, _)| history);
+    let drifts = config.colocations
+        .iter()
+        .filter_map(|c| match luftpost::check_drift(c, &config.sensors, &checked_measurements, sensor_states, history, now) {
+            Ok((drift, skipped)) => {
+                for e in skipped {
+                    print_error(&e.into());
+                }
+                drift
+            }
+            Err(e) => {
+                print_error(&e.into());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if print && !drifts.is_empty() {
+        println!("Co-located sensors:");
+        luftpost::print_drifts(drifts.iter().collect::<Vec<_>>().as_slice());
+    }
+    let drift_changes = update_drift_states(&drifts, sensor_states);
+    if let Some(ref state_dir) = config.general.state_dir {
+        let colocation_ids = drifts.iter().map(|d| &d.colocation.id).collect::<Vec<_>>();
+        save_sensor_states(&colocation_ids, sensor_states, state_dir)?;
     }
 
-    if let Some(ref smtp) = config.smtp {
+    // Sending one E-Mail must not prevent sending the others, e.g., the failure notifications
+    let mut mail_errors: Vec<Error> = Vec::new();
+    if let Some(mailer) = mailer {
         if print {
             println!("Sending E-Mails:");
         }
-        let mut mailer = Mailer::create_mailer(smtp)?;
+        let results = notifications
+            .into_iter()
+            .filter(|&(cm, _)| !recovered.contains(&cm.measurement.sensor.id))
+            .map(|(cm, previous_severity)| {
+                if print {
+                    match (previous_severity, cm.severity) {
+                        (Some(previous), Some(severity)) if previous != severity => println!(
+                            "{} because the level changed from {} to {}.", cm.measurement.sensor.name, previous, severity),
+                        (_, Some(severity)) => println!(
+                            "{} because a threshold has been exceeded at level {}.", cm.measurement.sensor.name, severity),
+                        (_, None) => println!("{} because a threshold is back to normal.", cm.measurement.sensor.name),
+                    }
+                }
+                mailer.mail_measurement(cm, previous_severity).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
+        let results = group_notifications
+            .into_iter()
+            .map(|(cg, previous_severity)| {
+                if print {
+                    match (previous_severity, cg.checked_measurement.severity) {
+                        (Some(previous), Some(severity)) if previous != severity => println!(
+                            "Group {} because the level changed from {} to {}.", cg.group.name, previous, severity),
+                        (_, Some(severity)) => println!(
+                            "Group {} because a threshold has been exceeded at level {}.", cg.group.name, severity),
+                        (_, None) => println!("Group {} because a threshold is back to normal.", cg.group.name),
+                    }
+                }
+                mailer.mail_group(cg, previous_severity).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
+        let results = drifts
+            .iter()
+            .filter(|d| drift_changes.contains(&d.colocation.id))
+            .map(|d| {
+                if print {
+                    if d.drifting {
+                        println!("Co-located sensors {} because their readings drift apart.", d.colocation.name);
+                    } else {
+                        println!("Co-located sensors {} because their readings agree again.", d.colocation.name);
+                    }
+                }
+                mailer.mail_maintenance(d).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
         let results = checked_measurements
             .iter()
-            .filter(|cm|
-                match cm.measurement.sensor.notification_condition.unwrap() {
-                    NotificationCondition::Always | NotificationCondition::ThresholdExceeded if cm.has_violations => true,
-                    NotificationCondition::OnChange if alarm_state_changed(
-                        sensor_states.as_ref(), &cm.measurement.sensor.id, cm.has_violations) => true,
-                    _ => false
+            // Unwrap is safe because notify_on_suspect is set during config parsing
+            .filter(|cm| suspects.contains(&cm.measurement.sensor.id) && cm.measurement.sensor.notify_on_suspect.unwrap())
+            .map(|cm| {
+                if print {
+                    println!("{} because the sensor seems to malfunction.", cm.measurement.sensor.name);
+                }
+                mailer.mail_suspect(cm).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
+        let results = failures
+            .iter()
+            // Unwrap is safe because notify_on_failure is set during config parsing
+            .filter(|f| f.sensor.notify_on_failure.unwrap())
+            .map(|f| {
+                if print {
+                    println!("{} because reading a measurement failed.", f.sensor.name);
                 }
-            )
+                mailer.mail_failure(f).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
+        let results = checked_measurements
+            .iter()
+            .filter(|cm| recovered.contains(&cm.measurement.sensor.id))
             .map(|cm| {
                 if print {
-                    if cm.has_violations {
-                        println!("{} because a threshold has been exceeded.", cm.measurement.sensor.name);
-                    } else {
-                        println!("{} because a threshold is back to normal.", cm.measurement.sensor.name);
-                    }
+                    println!("{} because the sensor is reachable again.", cm.measurement.sensor.name);
                 }
-                mailer.mail_measurement(cm).map_err(|e| e.into())
+                // Unwrap is safe because the state has just been updated
+                let sensor_state = sensor_states.get(&cm.measurement.sensor.id).unwrap();
+                mailer.mail_recovered(cm, sensor_state).map_err(|e| e.into())
             });
-        results.collect::<::std::result::Result<Vec<()>, Error>>()?;
+        mail_errors.extend(results.filter_map(|res| res.err()));
+
+        let results = failures
+            .iter()
+            .filter(|f| unreachable.contains(&f.sensor.id))
+            .map(|f| {
+                if print {
+                    println!("{} because the sensor is unreachable.", f.sensor.name);
+                }
+                // Unwrap is safe because the state has just been updated
+                let sensor_state = sensor_states.get(&f.sensor.id).unwrap();
+                mailer.mail_unreachable(&f.sensor, sensor_state).map_err(|e| e.into())
+            });
+        mail_errors.extend(results.filter_map(|res| res.err()));
     }
 
-    Ok(0)
+    if !mail_errors.is_empty() {
+        for e in &mail_errors {
+            print_error(e);
+        }
+        bail!(ErrorKind::FailedToSendEmails(mail_errors.len()));
+    }
+
+    Ok(failures.len())
 }
 
-fn alarm_state_changed(sensor_states: Option<&HashMap<SensorId, SensorState>>, sensor_id: &SensorId, has_violation: bool) -> bool {
-    if let Some(states) = sensor_states {
-        if let Some(state) = states.get(sensor_id) {
-            match state.alarm_state {
-                AlarmState::Normal if has_violation => true,
-                AlarmState::ThresholdExceeded if !has_violation => true,
-                _ => false
-            }
-        } else {
-            true
-        }
-    } else {
-        true
+fn notify(
+    cm: &CheckedMeasurement,
+    previous_alarm_states: &HashMap<SensorId, AlarmState>,
+    sensor_states: &HashMap<SensorId, SensorState>,
+) -> bool {
+    let sensor_id = &cm.measurement.sensor.id;
+    // Unwrap is safe because notification_condition is set during config parsing
+    match cm.measurement.sensor.notification_condition.unwrap() {
+        NotificationCondition::Always | NotificationCondition::ThresholdExceeded if cm.has_violations => true,
+        NotificationCondition::OnChange if alarm_state_changed(previous_alarm_states.get(sensor_id), sensor_states.get(sensor_id)) => true,
+        _ => false,
+    }
+}
+
+// Escalations as well as de-escalations of the level are changes; the sensor state decides when a level holds
+fn alarm_state_changed(previous_alarm_state: Option<&AlarmState>, sensor_state: Option<&SensorState>) -> bool {
+    match (previous_alarm_state, sensor_state) {
+        (Some(&AlarmState::Unreachable), _) => false,
+        (Some(previous), Some(state)) => *previous != state.alarm_state,
+        _ => true,
     }
 }
 
@@ -156,12 +550,27 @@ fn build_cli() -> App<'static, 'static> {
         .arg(Arg::with_name("print")
              .long("print")
              .help("Print results"))
+        .arg(Arg::with_name("daemon")
+             .short("d")
+             .long("daemon")
+             .help("Keeps running and polls sensors according to their intervals until SIGINT or SIGTERM"))
         .arg(Arg::with_name("check-config")
              .long("check-config")
              .help("Checks config and exits"))
         .arg(Arg::with_name("show-config")
              .long("show-config")
              .help("Prints config"))
+        .subcommand(SubCommand::with_name("stats")
+             .about("Prints statistics derived from the sensor states")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("exceedances")
+                  .about("Prints the number of days per year with a PM 10 daily mean above the exceedance limit")
+                  .arg(Arg::with_name("year")
+                       .long("year")
+                       .value_name("YEAR")
+                       .help("Sets the year; defaults to the current year")
+                       .takes_value(true)
+                       .validator(|y| y.parse::<i32>().map(|_| ()).map_err(|e| e.to_string())))))
         .arg(Arg::with_name("completions")
              .long("completions")
              .takes_value(true)
@@ -183,23 +592,174 @@ fn load_sensor_states<P: AsRef<Path>>(sensors: &[Sensor], state_dir: P) -> HashM
 
 }
 
-fn read_measurements(core: &mut Core, sensors: Vec<Sensor>) -> Result<Vec<Measurement>> {
-    let client = luftpost::create_sensor_reader(core);
+fn read_measurements(
+    core: &mut Core,
+    sources: &HashMap<SensorId, Box<MeasurementSource>>,
+    sensors: Vec<Sensor>
+) -> Result<Vec<::std::result::Result<Measurement, FailedMeasurement>>> {
+    let handle = core.handle();
     let work = sensors.into_iter().map(|s| {
-        let uri = s.data_uri.parse().unwrap();
-        let response = client.get(uri);
-        s.read_measurement(response)
+        // Unwrap is safe because there is a source for each configured sensor
+        let source = sources.get(&s.id).unwrap();
+        let sensor = s.clone();
+        source.read_measurement(s, &handle)
+            .then(move |res| -> Result<_> { Ok(res.map_err(|e| FailedMeasurement::new(sensor, &e))) })
     });
 
     let big_f = join_all(work);
-    core.run(big_f).map_err(|e| e.into())
+    core.run(big_f)
+}
+
+fn update_sensor_states<'a, I: IntoIterator<Item = &'a CheckedMeasurement>>(
+    checked_measurements: I,
+    sensor_states: &mut HashMap<SensorId, SensorState>,
+    timestamp: DateTime<Utc>,
+) -> Vec<SensorId> {
+    let mut recovered = Vec::new();
+    // Stale and suspect measurements neither raise nor clear alarms
+    for cm in checked_measurements.into_iter().filter(|cm| !cm.stale && !cm.suspect) {
+        let sensor_id = &cm.measurement.sensor.id;
+        let sensor_state = sensor_states.entry(sensor_id.clone()).or_insert_with(|| SensorState::new(sensor_id));
+        sensor_state.record_samples(&cm.measurement);
+        sensor_state.record_daily_mean(&cm.measurement);
+        sensor_state.record_last_reading(&cm.measurement, &cm.raw_data_values);
+        let sensor_recovered = if cm.humidity_suppressed {
+            sensor_state.record_humidity_suppressed_measurement(&cm.measurement.sensor, &cm.violations, timestamp)
+        } else {
+            sensor_state.record_measurement(&cm.measurement.sensor, &cm.violations, timestamp)
+        };
+        if sensor_recovered {
+            recovered.push(sensor_id.clone());
+        }
+    }
+
+    recovered
+}
+
+fn record_failures(
+    failures: &[FailedMeasurement],
+    sensor_states: &mut HashMap<SensorId, SensorState>,
+    timestamp: DateTime<Utc>,
+) -> Vec<SensorId> {
+    let mut unreachable = Vec::new();
+    for f in failures {
+        let sensor_id = &f.sensor.id;
+        let sensor_state = sensor_states.entry(sensor_id.clone()).or_insert_with(|| SensorState::new(sensor_id));
+        if sensor_state.record_failure(&f.sensor, timestamp) {
+            unreachable.push(sensor_id.clone());
+        }
+    }
+
+    unreachable
+}
+
+// Returns the sensors whose readings just became suspect
+fn record_suspects(checked_measurements: &[CheckedMeasurement], sensor_states: &mut HashMap<SensorId, SensorState>) -> Vec<SensorId> {
+    let mut suspects = Vec::new();
+    for cm in checked_measurements.iter().filter(|cm| !cm.stale) {
+        let sensor_id = &cm.measurement.sensor.id;
+        let sensor_state = sensor_states.entry(sensor_id.clone()).or_insert_with(|| SensorState::new(sensor_id));
+        sensor_state.record_particulates(&cm.measurement, &cm.raw_particulates());
+        if cm.suspect && !sensor_state.suspect {
+            suspects.push(sensor_id.clone());
+        }
+        sensor_state.suspect = cm.suspect;
+    }
+
+    suspects
+}
+
+// Co-location states only track whether the sensors drift apart; maintenance notifications are sent when this changes
+fn update_drift_states(drifts: &[Drift], sensor_states: &mut HashMap<SensorId, SensorState>) -> Vec<SensorId> {
+    let mut changed = Vec::new();
+    for d in drifts {
+        let id = &d.colocation.id;
+        let alarm_state = if d.drifting { AlarmState::Alert } else { AlarmState::Normal };
+        let state = sensor_states.entry(id.clone()).or_insert_with(|| SensorState::new(id));
+        if state.alarm_state != alarm_state {
+            state.alarm_state = alarm_state;
+            changed.push(id.clone());
+        }
+    }
+
+    changed
+}
+
+// Stale measurements are not recorded because their values are attributed to the time they have been fetched;
+// suspect measurements are not recorded in order to keep the history free of malfunctions
+fn record_history(
+    checked_measurements: &[CheckedMeasurement],
+    history: &History,
+    retention: chrono::Duration,
+    now: DateTime<Utc>,
+) -> luftpost::history::Result<()> {
+    for cm in checked_measurements.iter().filter(|cm| !cm.stale && !cm.suspect) {
+        history.append(&cm.measurement)?;
+        // Pruning rewrites the history, so it is only done about once a day
+        let sensor_id = &cm.measurement.sensor.id;
+        let before = now - retention;
+        if history.oldest(sensor_id)?.map(|oldest| oldest < before - chrono::Duration::days(1)).unwrap_or(false) {
+            let (_, skipped) = history.prune(sensor_id, before)?;
+            for e in skipped {
+                print_error(&e.into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_error(e: &Error) {
+    use error_chain::ChainedError;
+    eprintln!("{}", e.display_chain());
+}
+
+fn history(config: &Config) -> Option<(History, chrono::Duration)> {
+    let retention = config.general.history_retention.as_ref().and_then(|r| config::parse_duration(r))?;
+    config.general.state_dir.as_ref().map(|state_dir| (History::new(state_dir), retention))
+}
+
+// Daily means are derived from the history if it is kept for at least a year
+fn keeps_daily_means(retention: chrono::Duration) -> bool {
+    retention >= chrono::Duration::days(365)
 }
 
-fn save_sensor_states<P: AsRef<Path>>(checked_measurements: &[CheckedMeasurement], state_dir: P) -> Result<()> {
-    for cm in checked_measurements {
-        let state = if cm.has_violations { AlarmState::ThresholdExceeded } else { AlarmState::Normal };
-        let sensor_state = SensorState { sensor_id: cm.measurement.sensor.id.clone(), alarm_state: state };
-        sensor_state.save(&state_dir)?;
+fn daily_means_history(config: &Config) -> Option<(History, chrono::Duration)> {
+    history(config).filter(|&(_, retention)| keeps_daily_means(retention))
+}
+
+// Means, rates of change, and the NowCast are derived from the history if it is kept;
+// if reading the history fails, the measurements are checked with what the state holds
+fn load_history(measurements: &[Measurement], history: Option<&(History, chrono::Duration)>, sensor_states: &mut HashMap<SensorId, SensorState>) {
+    let (history, load_daily_means) = match history {
+        Some(&(ref history, retention)) => (history, keeps_daily_means(retention)),
+        None => return,
+    };
+    for m in measurements {
+        let sensor_state = sensor_states.entry(m.sensor.id.clone()).or_insert_with(|| SensorState::new(&m.sensor.id));
+        match sensor_state.load_samples(history, m) {
+            Ok(skipped) => for e in skipped {
+                print_error(&e.into());
+            },
+            Err(e) => print_error(&e.into()),
+        }
+        if load_daily_means {
+            let year = m.timestamp.unwrap_or(m.fetched_at).year();
+            match sensor_state.load_daily_means(history, year) {
+                Ok(skipped) => for e in skipped {
+                    print_error(&e.into());
+                },
+                Err(e) => print_error(&e.into()),
+            }
+        }
+    }
+}
+
+fn save_sensor_states<P: AsRef<Path>>(sensor_ids: &[&SensorId], sensor_states: &HashMap<SensorId, SensorState>, state_dir: P) -> Result<()> {
+    for sensor_id in sensor_ids {
+        if let Some(sensor_state) = sensor_states.get(*sensor_id) {
+            sensor_state.save(&state_dir)?;
+        }
     }
 
     Ok(())
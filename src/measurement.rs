@@ -1,3 +1,4 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use sensor::Sensor;
 use std::fmt;
 
@@ -11,6 +12,10 @@ error_chain! {
             description("invalid value")
             display("invalid value: '{}'", value_str)
         }
+        InvalidTimestamp(timestamp_str: String) {
+            description("invalid timestamp")
+            display("invalid timestamp: '{}'", timestamp_str)
+        }
     }
     links {
         WireMeasurementDecodingFailed(wire::Error, wire::ErrorKind);
@@ -22,13 +27,55 @@ error_chain! {
 pub enum Value {
     SDS_P1(f32),
     SDS_P2(f32),
+    PMS_P0(f32),
+    PMS_P1(f32),
+    PMS_P2(f32),
+    HPM_P1(f32),
+    HPM_P2(f32),
+    SPS30_P0(f32),
+    SPS30_P1(f32),
+    SPS30_P2(f32),
+    SPS30_P4(f32),
+    SPS30_N05(f32),
+    SPS30_N1(f32),
+    SPS30_N25(f32),
+    SPS30_N4(f32),
+    SPS30_N10(f32),
+    SPS30_TS(f32),
+    NPM_P0(f32),
+    NPM_P1(f32),
+    NPM_P2(f32),
+    NPM_N1(f32),
+    NPM_N10(f32),
+    NPM_N25(f32),
     TEMPERATURE(f32),
     HUMIDITY(f32),
+    BMP_TEMPERATURE(f32),
+    BMP_PRESSURE(f32),
+    BMP280_TEMPERATURE(f32),
+    BMP280_PRESSURE(f32),
+    BME280_TEMPERATURE(f32),
+    BME280_HUMIDITY(f32),
+    BME280_PRESSURE(f32),
+    SHT3X_TEMPERATURE(f32),
+    SHT3X_HUMIDITY(f32),
+    HTU21D_TEMPERATURE(f32),
+    HTU21D_HUMIDITY(f32),
+    DS18B20_TEMPERATURE(f32),
+    SCD30_TEMPERATURE(f32),
+    SCD30_HUMIDITY(f32),
+    SCD30_CO2(f32),
+    DNMS_NOISE_LAEQ(f32),
+    DNMS_NOISE_LA_MIN(f32),
+    DNMS_NOISE_LA_MAX(f32),
+    GPS_LAT(f32),
+    GPS_LON(f32),
+    GPS_HEIGHT(f32),
     SAMPLES(f32),
     MIN_MICRO(f32),
     MAX_MICRO(f32),
     SIGNAL(f32),
-    UNKNOWN(String),
+    UNKNOWN(String, String),
 }
 
 impl<'a> From<(&'a str, f32)> for Value {
@@ -38,66 +85,424 @@ impl<'a> From<(&'a str, f32)> for Value {
         match upper {
             "SDS_P1" => Value::SDS_P1(value),
             "SDS_P2" => Value::SDS_P2(value),
+            "PMS_P0" => Value::PMS_P0(value),
+            "PMS_P1" => Value::PMS_P1(value),
+            "PMS_P2" => Value::PMS_P2(value),
+            "HPM_P1" => Value::HPM_P1(value),
+            "HPM_P2" => Value::HPM_P2(value),
+            "SPS30_P0" => Value::SPS30_P0(value),
+            "SPS30_P1" => Value::SPS30_P1(value),
+            "SPS30_P2" => Value::SPS30_P2(value),
+            "SPS30_P4" => Value::SPS30_P4(value),
+            "SPS30_N05" => Value::SPS30_N05(value),
+            "SPS30_N1" => Value::SPS30_N1(value),
+            "SPS30_N25" => Value::SPS30_N25(value),
+            "SPS30_N4" => Value::SPS30_N4(value),
+            "SPS30_N10" => Value::SPS30_N10(value),
+            "SPS30_TS" => Value::SPS30_TS(value),
+            "NPM_P0" => Value::NPM_P0(value),
+            "NPM_P1" => Value::NPM_P1(value),
+            "NPM_P2" => Value::NPM_P2(value),
+            "NPM_N1" => Value::NPM_N1(value),
+            "NPM_N10" => Value::NPM_N10(value),
+            "NPM_N25" => Value::NPM_N25(value),
             "TEMPERATURE" => Value::TEMPERATURE(value),
             "HUMIDITY" => Value::HUMIDITY(value),
+            "BMP_TEMPERATURE" => Value::BMP_TEMPERATURE(value),
+            "BMP_PRESSURE" => Value::BMP_PRESSURE(value),
+            "BMP280_TEMPERATURE" => Value::BMP280_TEMPERATURE(value),
+            "BMP280_PRESSURE" => Value::BMP280_PRESSURE(value),
+            "BME280_TEMPERATURE" => Value::BME280_TEMPERATURE(value),
+            "BME280_HUMIDITY" => Value::BME280_HUMIDITY(value),
+            "BME280_PRESSURE" => Value::BME280_PRESSURE(value),
+            "SHT3X_TEMPERATURE" => Value::SHT3X_TEMPERATURE(value),
+            "SHT3X_HUMIDITY" => Value::SHT3X_HUMIDITY(value),
+            "HTU21D_TEMPERATURE" => Value::HTU21D_TEMPERATURE(value),
+            "HTU21D_HUMIDITY" => Value::HTU21D_HUMIDITY(value),
+            "DS18B20_TEMPERATURE" => Value::DS18B20_TEMPERATURE(value),
+            "SCD30_TEMPERATURE" => Value::SCD30_TEMPERATURE(value),
+            "SCD30_HUMIDITY" => Value::SCD30_HUMIDITY(value),
+            "SCD30_CO2" => Value::SCD30_CO2(value),
+            "DNMS_NOISE_LAEQ" => Value::DNMS_NOISE_LAEQ(value),
+            "DNMS_NOISE_LA_MIN" => Value::DNMS_NOISE_LA_MIN(value),
+            "DNMS_NOISE_LA_MAX" => Value::DNMS_NOISE_LA_MAX(value),
+            "GPS_LAT" => Value::GPS_LAT(value),
+            "GPS_LON" => Value::GPS_LON(value),
+            "GPS_HEIGHT" => Value::GPS_HEIGHT(value),
             "SAMPLES" => Value::SAMPLES(value),
             "MIN_MICRO" => Value::MIN_MICRO(value),
             "MAX_MICRO" => Value::MAX_MICRO(value),
             "SIGNAL" => Value::SIGNAL(value),
-            _ => Value::UNKNOWN(type_str.to_string()),
+            _ => Value::UNKNOWN(type_str.to_string(), value.to_string()),
+        }
+    }
+}
+
+impl Value {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Value::SDS_P1(_) => "PM 10",
+            Value::SDS_P2(_) => "PM 2.5",
+            Value::PMS_P0(_) => "PMS PM 1",
+            Value::PMS_P1(_) => "PMS PM 10",
+            Value::PMS_P2(_) => "PMS PM 2.5",
+            Value::HPM_P1(_) => "HPM PM 10",
+            Value::HPM_P2(_) => "HPM PM 2.5",
+            Value::SPS30_P0(_) => "SPS30 PM 1",
+            Value::SPS30_P1(_) => "SPS30 PM 10",
+            Value::SPS30_P2(_) => "SPS30 PM 2.5",
+            Value::SPS30_P4(_) => "SPS30 PM 4",
+            Value::SPS30_N05(_) => "SPS30 NC 0.5",
+            Value::SPS30_N1(_) => "SPS30 NC 1",
+            Value::SPS30_N25(_) => "SPS30 NC 2.5",
+            Value::SPS30_N4(_) => "SPS30 NC 4",
+            Value::SPS30_N10(_) => "SPS30 NC 10",
+            Value::SPS30_TS(_) => "SPS30 typical particle size",
+            Value::NPM_P0(_) => "NPM PM 1",
+            Value::NPM_P1(_) => "NPM PM 10",
+            Value::NPM_P2(_) => "NPM PM 2.5",
+            Value::NPM_N1(_) => "NPM NC 1",
+            Value::NPM_N10(_) => "NPM NC 10",
+            Value::NPM_N25(_) => "NPM NC 2.5",
+            Value::TEMPERATURE(_) => "Temperature",
+            Value::HUMIDITY(_) => "Humidity",
+            Value::BMP_TEMPERATURE(_) => "BMP temperature",
+            Value::BMP_PRESSURE(_) => "BMP pressure",
+            Value::BMP280_TEMPERATURE(_) => "BMP280 temperature",
+            Value::BMP280_PRESSURE(_) => "BMP280 pressure",
+            Value::BME280_TEMPERATURE(_) => "BME280 temperature",
+            Value::BME280_HUMIDITY(_) => "BME280 humidity",
+            Value::BME280_PRESSURE(_) => "BME280 pressure",
+            Value::SHT3X_TEMPERATURE(_) => "SHT3x temperature",
+            Value::SHT3X_HUMIDITY(_) => "SHT3x humidity",
+            Value::HTU21D_TEMPERATURE(_) => "HTU21D temperature",
+            Value::HTU21D_HUMIDITY(_) => "HTU21D humidity",
+            Value::DS18B20_TEMPERATURE(_) => "DS18B20 temperature",
+            Value::SCD30_TEMPERATURE(_) => "SCD30 temperature",
+            Value::SCD30_HUMIDITY(_) => "SCD30 humidity",
+            Value::SCD30_CO2(_) => "SCD30 CO2",
+            Value::DNMS_NOISE_LAEQ(_) => "Noise LAeq",
+            Value::DNMS_NOISE_LA_MIN(_) => "Noise LA min",
+            Value::DNMS_NOISE_LA_MAX(_) => "Noise LA max",
+            Value::GPS_LAT(_) => "GPS latitude",
+            Value::GPS_LON(_) => "GPS longitude",
+            Value::GPS_HEIGHT(_) => "GPS height",
+            Value::SAMPLES(_) => "Samples",
+            Value::MIN_MICRO(_) => "Min. micro",
+            Value::MAX_MICRO(_) => "Max. micro",
+            Value::SIGNAL(_) => "Wifi signal",
+            Value::UNKNOWN(_, _) => "Unknown",
+        }
+    }
+
+    pub fn value_type(&self) -> &str {
+        match *self {
+            Value::SDS_P1(_) => "SDS_P1",
+            Value::SDS_P2(_) => "SDS_P2",
+            Value::PMS_P0(_) => "PMS_P0",
+            Value::PMS_P1(_) => "PMS_P1",
+            Value::PMS_P2(_) => "PMS_P2",
+            Value::HPM_P1(_) => "HPM_P1",
+            Value::HPM_P2(_) => "HPM_P2",
+            Value::SPS30_P0(_) => "SPS30_P0",
+            Value::SPS30_P1(_) => "SPS30_P1",
+            Value::SPS30_P2(_) => "SPS30_P2",
+            Value::SPS30_P4(_) => "SPS30_P4",
+            Value::SPS30_N05(_) => "SPS30_N05",
+            Value::SPS30_N1(_) => "SPS30_N1",
+            Value::SPS30_N25(_) => "SPS30_N25",
+            Value::SPS30_N4(_) => "SPS30_N4",
+            Value::SPS30_N10(_) => "SPS30_N10",
+            Value::SPS30_TS(_) => "SPS30_TS",
+            Value::NPM_P0(_) => "NPM_P0",
+            Value::NPM_P1(_) => "NPM_P1",
+            Value::NPM_P2(_) => "NPM_P2",
+            Value::NPM_N1(_) => "NPM_N1",
+            Value::NPM_N10(_) => "NPM_N10",
+            Value::NPM_N25(_) => "NPM_N25",
+            Value::TEMPERATURE(_) => "TEMPERATURE",
+            Value::HUMIDITY(_) => "HUMIDITY",
+            Value::BMP_TEMPERATURE(_) => "BMP_TEMPERATURE",
+            Value::BMP_PRESSURE(_) => "BMP_PRESSURE",
+            Value::BMP280_TEMPERATURE(_) => "BMP280_TEMPERATURE",
+            Value::BMP280_PRESSURE(_) => "BMP280_PRESSURE",
+            Value::BME280_TEMPERATURE(_) => "BME280_TEMPERATURE",
+            Value::BME280_HUMIDITY(_) => "BME280_HUMIDITY",
+            Value::BME280_PRESSURE(_) => "BME280_PRESSURE",
+            Value::SHT3X_TEMPERATURE(_) => "SHT3X_TEMPERATURE",
+            Value::SHT3X_HUMIDITY(_) => "SHT3X_HUMIDITY",
+            Value::HTU21D_TEMPERATURE(_) => "HTU21D_TEMPERATURE",
+            Value::HTU21D_HUMIDITY(_) => "HTU21D_HUMIDITY",
+            Value::DS18B20_TEMPERATURE(_) => "DS18B20_TEMPERATURE",
+            Value::SCD30_TEMPERATURE(_) => "SCD30_TEMPERATURE",
+            Value::SCD30_HUMIDITY(_) => "SCD30_HUMIDITY",
+            Value::SCD30_CO2(_) => "SCD30_CO2",
+            Value::DNMS_NOISE_LAEQ(_) => "DNMS_NOISE_LAEQ",
+            Value::DNMS_NOISE_LA_MIN(_) => "DNMS_NOISE_LA_MIN",
+            Value::DNMS_NOISE_LA_MAX(_) => "DNMS_NOISE_LA_MAX",
+            Value::GPS_LAT(_) => "GPS_LAT",
+            Value::GPS_LON(_) => "GPS_LON",
+            Value::GPS_HEIGHT(_) => "GPS_HEIGHT",
+            Value::SAMPLES(_) => "SAMPLES",
+            Value::MIN_MICRO(_) => "MIN_MICRO",
+            Value::MAX_MICRO(_) => "MAX_MICRO",
+            Value::SIGNAL(_) => "SIGNAL",
+            Value::UNKNOWN(ref name, _) => name,
+        }
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        match *self {
+            Value::SDS_P1(v)
+            | Value::SDS_P2(v)
+            | Value::PMS_P0(v)
+            | Value::PMS_P1(v)
+            | Value::PMS_P2(v)
+            | Value::HPM_P1(v)
+            | Value::HPM_P2(v)
+            | Value::SPS30_P0(v)
+            | Value::SPS30_P1(v)
+            | Value::SPS30_P2(v)
+            | Value::SPS30_P4(v)
+            | Value::SPS30_N05(v)
+            | Value::SPS30_N1(v)
+            | Value::SPS30_N25(v)
+            | Value::SPS30_N4(v)
+            | Value::SPS30_N10(v)
+            | Value::SPS30_TS(v)
+            | Value::NPM_P0(v)
+            | Value::NPM_P1(v)
+            | Value::NPM_P2(v)
+            | Value::NPM_N1(v)
+            | Value::NPM_N10(v)
+            | Value::NPM_N25(v)
+            | Value::TEMPERATURE(v)
+            | Value::HUMIDITY(v)
+            | Value::BMP_TEMPERATURE(v)
+            | Value::BMP_PRESSURE(v)
+            | Value::BMP280_TEMPERATURE(v)
+            | Value::BMP280_PRESSURE(v)
+            | Value::BME280_TEMPERATURE(v)
+            | Value::BME280_HUMIDITY(v)
+            | Value::BME280_PRESSURE(v)
+            | Value::SHT3X_TEMPERATURE(v)
+            | Value::SHT3X_HUMIDITY(v)
+            | Value::HTU21D_TEMPERATURE(v)
+            | Value::HTU21D_HUMIDITY(v)
+            | Value::DS18B20_TEMPERATURE(v)
+            | Value::SCD30_TEMPERATURE(v)
+            | Value::SCD30_HUMIDITY(v)
+            | Value::SCD30_CO2(v)
+            | Value::DNMS_NOISE_LAEQ(v)
+            | Value::DNMS_NOISE_LA_MIN(v)
+            | Value::DNMS_NOISE_LA_MAX(v)
+            | Value::GPS_LAT(v)
+            | Value::GPS_LON(v)
+            | Value::GPS_HEIGHT(v)
+            | Value::SAMPLES(v)
+            | Value::MIN_MICRO(v)
+            | Value::MAX_MICRO(v)
+            | Value::SIGNAL(v) => Some(v),
+            Value::UNKNOWN(_, _) => None,
+        }
+    }
+
+    pub fn pm10(&self) -> Option<f32> {
+        match *self {
+            Value::SDS_P1(v) | Value::PMS_P1(v) | Value::HPM_P1(v) | Value::SPS30_P1(v) | Value::NPM_P1(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn pm2(&self) -> Option<f32> {
+        match *self {
+            Value::SDS_P2(v) | Value::PMS_P2(v) | Value::HPM_P2(v) | Value::SPS30_P2(v) | Value::NPM_P2(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn pm1(&self) -> Option<f32> {
+        match *self {
+            Value::PMS_P0(v) | Value::SPS30_P0(v) | Value::NPM_P0(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn humidity(&self) -> Option<f32> {
+        match *self {
+            Value::HUMIDITY(v) | Value::BME280_HUMIDITY(v) | Value::SHT3X_HUMIDITY(v) | Value::HTU21D_HUMIDITY(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn is_particulates(&self) -> bool {
+        self.pm10().or_else(|| self.pm2()).or_else(|| self.pm1()).is_some()
+    }
+
+    // Returns the same value type with another value, e.g., a corrected one
+    pub fn with_value(&self, value: f32) -> Value {
+        Value::from((self.value_type(), value))
+    }
+
+    // 'pm10', 'pm2', and 'pm1' select the particulates concentration regardless of the sensor type
+    pub fn select(&self, value_type: &str) -> Option<f32> {
+        match &value_type.to_lowercase()[..] {
+            "pm10" => self.pm10(),
+            "pm2" => self.pm2(),
+            "pm1" => self.pm1(),
+            value_type if self.value_type().to_lowercase() == value_type => self.value(),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let printable = match *self {
-            Value::SDS_P1(v) => format!("PM 10 = {}", v),
-            Value::SDS_P2(v) => format!("PM 2.5 = {}", v),
-            Value::TEMPERATURE(v) => format!("Temperature = {}", v),
-            Value::HUMIDITY(v) => format!("Humidity = {}", v),
-            Value::SAMPLES(v) => format!("Samples = {}", v),
-            Value::MIN_MICRO(v) => format!("Min micro = {}", v),
-            Value::MAX_MICRO(v) => format!("Max Micro = {}", v),
-            Value::SIGNAL(v) => format!("Wifi signal = {}", v),
-            Value::UNKNOWN(ref v) => format!("Unknown = {}", v),
-        };
-        write!(f, "{}", printable)
+        match *self {
+            Value::UNKNOWN(ref name, ref v) => write!(f, "Unknown {} = {}", name, v),
+            ref value => write!(f, "{} = {}", value.name(), value.value().unwrap_or(0.0)),
+        }
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub country: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct Measurement {
     pub sensor: Sensor,
     pub software_version: String,
     pub data_values: Vec<Value>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub location: Option<Location>,
+    pub warnings: Vec<String>,
+    pub fetched_at: DateTime<Utc>,
+    pub age: Option<u64>,
 }
 
 impl Measurement {
     pub fn from_json(sensor: Sensor, json: &str) -> Result<Self> {
         let wire_measurement = wire::decode_json_to_measurement(json)?;
-        wire_to_measurement(sensor, wire_measurement)
+        wire_to_measurement(sensor, wire_measurement, Utc::now())
+    }
+
+    pub fn from_api_json(sensor: Sensor, json: &str) -> Result<Self> {
+        let wire_measurement = wire::decode_api_json_to_measurement(json)?;
+        wire_to_measurement(sensor, wire_measurement, Utc::now())
+    }
+
+    // The sensor reported age takes precedence over the reading's timestamp
+    pub fn reading_age(&self) -> Option<Duration> {
+        match (self.age, self.timestamp) {
+            (Some(age), _) => Some(Duration::seconds(age as i64)),
+            (None, Some(timestamp)) => Some(self.fetched_at.signed_duration_since(timestamp)),
+            (None, None) => None,
+        }
+    }
+
+    // Readings without a timestamp are dated back by their age, which the sensor reports in whole seconds
+    pub fn sampled_at(&self) -> DateTime<Utc> {
+        self.timestamp.unwrap_or_else(|| self.fetched_at - self.reading_age().unwrap_or_else(Duration::zero))
+    }
+
+    // Without a timestamp or an age, every reading is taken to be a new sample
+    pub fn is_same_sample(&self, sampled_at: DateTime<Utc>) -> bool {
+        match (self.timestamp, self.age) {
+            (Some(timestamp), _) => timestamp == sampled_at,
+            (None, Some(_)) => self.sampled_at().signed_duration_since(sampled_at).num_milliseconds().abs() < 1000,
+            (None, None) => false,
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        match (self.sensor.max_age, self.reading_age()) {
+            (Some(max_age), Some(age)) => age > Duration::seconds(max_age as i64),
+            _ => false,
+        }
     }
 }
 
-fn wire_to_measurement(sensor: Sensor, wire: wire::Measurement) -> Result<Measurement> {
+fn wire_to_measurement(sensor: Sensor, wire: wire::Measurement, fetched_at: DateTime<Utc>) -> Result<Measurement> {
+    let strict = sensor.strict.unwrap_or(false);
     let mut data_values = Vec::new();
+    let mut warnings = Vec::new();
 
+    // In lenient mode, unknown value types and invalid values are kept as raw values and reported as warnings
     for dv in wire.data_values {
         let value_str = dv.value;
-        let value = value_str
-            .parse::<f32>()
-            .chain_err(|| ErrorKind::InvalidValue(value_str.to_string()))?;
+        let value = match value_str.parse::<f32>() {
+            Ok(value) => value,
+            Err(_) if !strict => {
+                warnings.push(format!("invalid value '{}' for value type '{}'", value_str, dv.value_type));
+                data_values.push(Value::UNKNOWN(dv.value_type, value_str));
+                continue;
+            }
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::InvalidValue(value_str.to_string()))),
+        };
         match Value::from((&dv.value_type[..], value)) {
-            Value::UNKNOWN(str) => bail!(ErrorKind::InvalidValueType(str)),
+            Value::UNKNOWN(name, _) => {
+                if strict {
+                    bail!(ErrorKind::InvalidValueType(name));
+                }
+                warnings.push(format!("unknown value type '{}'", name));
+                data_values.push(Value::UNKNOWN(name, value_str));
+            }
             vt => data_values.push(vt),
         }
     }
 
+    let timestamp = match wire.timestamp {
+        Some(ref ts) => Some(
+            Utc.datetime_from_str(ts, wire::API_TIMESTAMP_FORMAT)
+                .chain_err(|| ErrorKind::InvalidTimestamp(ts.to_string()))?,
+        ),
+        None => None,
+    };
+    let location = match wire.location {
+        Some(ref l) => Some(wire_to_location(l)?),
+        None => None,
+    };
+    let age = match wire.age {
+        Some(ref age_str) => match age_str.parse::<u64>() {
+            Ok(age) => Some(age),
+            Err(_) if !strict => {
+                warnings.push(format!("invalid age '{}'", age_str));
+                None
+            }
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::InvalidValue(age_str.to_string()))),
+        },
+        None => None,
+    };
+
     Ok(Measurement {
         sensor: sensor,
         software_version: wire.software_version,
         data_values: data_values,
+        timestamp: timestamp,
+        location: location,
+        warnings: warnings,
+        fetched_at: fetched_at,
+        age: age,
+    })
+}
+
+fn wire_to_location(wire: &wire::Location) -> Result<Location> {
+    let parse = |value_str: &str| value_str.parse::<f64>().chain_err(|| ErrorKind::InvalidValue(value_str.to_string()));
+    let altitude = match wire.altitude {
+        Some(ref altitude) => Some(parse(altitude)?),
+        None => None,
+    };
+
+    Ok(Location {
+        latitude: parse(&wire.latitude)?,
+        longitude: parse(&wire.longitude)?,
+        altitude: altitude,
+        country: wire.country.clone(),
     })
 }
 
@@ -115,12 +520,28 @@ mod test {
         assert_eq!(Value::MIN_MICRO(15.0), ("MIN_MICRO", 15.0).into());
         assert_eq!(Value::MAX_MICRO(16.0), ("MAX_MICRO", 16.0).into());
         assert_eq!(Value::SIGNAL(17.0), ("SIGNAL", 17.0).into());
+        assert_eq!(Value::PMS_P0(18.0), ("PMS_P0", 18.0).into());
+        assert_eq!(Value::SPS30_N05(19.0), ("SPS30_N05", 19.0).into());
+        assert_eq!(Value::BME280_PRESSURE(20.0), ("BME280_pressure", 20.0).into());
+        assert_eq!(Value::SHT3X_HUMIDITY(21.0), ("SHT3X_humidity", 21.0).into());
+        assert_eq!(Value::DS18B20_TEMPERATURE(22.0), ("DS18B20_temperature", 22.0).into());
+        assert_eq!(Value::DNMS_NOISE_LAEQ(23.0), ("DNMS_noise_LAeq", 23.0).into());
+    }
+
+    #[test]
+    fn value_pm_okay() -> () {
+        assert_eq!(Value::SDS_P1(10.0).pm10(), Some(10.0));
+        assert_eq!(Value::SPS30_P1(10.0).pm10(), Some(10.0));
+        assert_eq!(Value::HPM_P2(2.5).pm2(), Some(2.5));
+        assert_eq!(Value::NPM_P0(1.0).pm1(), Some(1.0));
+        assert_eq!(Value::SDS_P1(10.0).pm2(), None);
+        assert_eq!(Value::TEMPERATURE(10.0).pm10(), None);
     }
 
     #[test]
     fn value_type_from_str_unknown() -> () {
         assert_eq!(
-            Value::UNKNOWN("does not exists".to_string()),
+            Value::UNKNOWN("does not exists".to_string(), "10".to_string()),
             ("does not exists", 10.0).into()
         );
     }
@@ -164,6 +585,9 @@ mod test {
         let wire = wire::Measurement {
             software_version: "NRZ-2017-089".to_string(),
             data_values: w_data_values,
+            timestamp: None,
+            location: None,
+            age: None,
         };
 
         let mut data_values = Vec::new();
@@ -179,13 +603,88 @@ mod test {
             sensor: Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"),
             software_version: "NRZ-2017-089".to_string(),
             data_values: data_values,
+            timestamp: None,
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: Utc.ymd(2018, 1, 24).and_hms(12, 0, 3),
+            age: None,
         };
 
-        let m = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire);
+        let m = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire, Utc.ymd(2018, 1, 24).and_hms(12, 0, 3));
 
         assert_eq!(m.unwrap(), expected);
     }
 
+    #[test]
+    fn wire_to_measurement_timestamp_and_location_ok() -> () {
+        let wire = wire::Measurement {
+            software_version: String::new(),
+            data_values: Vec::new(),
+            timestamp: Some("2018-01-24 12:00:03".to_string()),
+            location: Some(wire::Location {
+                latitude: "48.800".to_string(),
+                longitude: "9.200".to_string(),
+                altitude: Some("250.2".to_string()),
+                country: Some("DE".to_string()),
+            }),
+            age: None,
+        };
+
+        let m = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire, Utc::now()).unwrap();
+
+        assert_eq!(m.timestamp, Some(Utc.ymd(2018, 1, 24).and_hms(12, 0, 3)));
+        assert_eq!(
+            m.location,
+            Some(Location {
+                latitude: 48.8,
+                longitude: 9.2,
+                altitude: Some(250.2),
+                country: Some("DE".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn wire_to_measurement_age_ok() -> () {
+        let wire = wire::Measurement {
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: Vec::new(),
+            timestamp: None,
+            location: None,
+            age: Some("37".to_string()),
+        };
+
+        let m = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire, Utc::now()).unwrap();
+
+        assert_eq!(m.age, Some(37));
+        assert_eq!(m.reading_age(), Some(Duration::seconds(37)));
+    }
+
+    #[test]
+    fn measurement_is_stale() -> () {
+        let sensor = Sensor {
+            max_age: Some(600),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let fetched_at = Utc.ymd(2018, 1, 24).and_hms(12, 0, 3);
+        let mut m = Measurement {
+            sensor: sensor,
+            software_version: String::new(),
+            data_values: Vec::new(),
+            timestamp: Some(Utc.ymd(2018, 1, 24).and_hms(11, 55, 3)),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: fetched_at,
+            age: None,
+        };
+
+        assert!(!m.is_stale());
+        m.timestamp = Some(Utc.ymd(2018, 1, 24).and_hms(11, 30, 3));
+        assert!(m.is_stale());
+        m.age = Some(10);
+        assert!(!m.is_stale());
+    }
+
     #[test]
     fn wire_to_measurement_unknown_value_type() -> () {
         let w_data_values: Vec<wire::DataValue> = vec![
@@ -197,9 +696,17 @@ mod test {
         let wire = wire::Measurement {
             software_version: "NRZ-2017-089".to_string(),
             data_values: w_data_values,
+            timestamp: None,
+            location: None,
+            age: None,
+        };
+
+        let sensor = Sensor {
+            strict: Some(true),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
         };
 
-        let res = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire);
+        let res = wire_to_measurement(sensor, wire, Utc::now());
 
         match res {
             Err(Error(ErrorKind::InvalidValueType(_), _)) => assert!(true),
@@ -207,6 +714,32 @@ mod test {
         }
     }
 
+    #[test]
+    fn from_api_json_strict() -> () {
+        let sensor = Sensor {
+            strict: Some(true),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let bme280 = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12346,"pin":"11","sensor_type":{"id":17,"name":"BME280","manufacturer":"Bosch"}},"sensordatavalues":[{"id":2,"value":"3.40","value_type":"temperature"},{"id":3,"value":"81.20","value_type":"humidity"},{"id":4,"value":"98250.43","value_type":"pressure"},{"id":5,"value":"101330.12","value_type":"pressure_at_sealevel"}]}]"#;
+        let dnms = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12347,"pin":"15","sensor_type":{"id":29,"name":"DNMS (Laerm)","manufacturer":"Luftdaten.info"}},"sensordatavalues":[{"id":2,"value":"52.30","value_type":"noise_LAeq"},{"id":3,"value":"41.10","value_type":"noise_LA_min"},{"id":4,"value":"67.80","value_type":"noise_LA_max"}]}]"#;
+
+        let m = Measurement::from_api_json(sensor.clone(), bme280).unwrap();
+
+        assert_eq!(
+            m.data_values,
+            vec![Value::BME280_TEMPERATURE(3.4), Value::BME280_HUMIDITY(81.2), Value::BME280_PRESSURE(98250.43)]
+        );
+        assert!(m.warnings.is_empty());
+
+        let m = Measurement::from_api_json(sensor, dnms).unwrap();
+
+        assert_eq!(
+            m.data_values,
+            vec![Value::DNMS_NOISE_LAEQ(52.3), Value::DNMS_NOISE_LA_MIN(41.1), Value::DNMS_NOISE_LA_MAX(67.8)]
+        );
+        assert!(m.warnings.is_empty());
+    }
+
     #[test]
     fn wire_to_measurement_invalid_value() -> () {
         let w_data_values: Vec<wire::DataValue> = vec![
@@ -218,18 +751,64 @@ mod test {
         let wire = wire::Measurement {
             software_version: "NRZ-2017-089".to_string(),
             data_values: w_data_values,
+            timestamp: None,
+            location: None,
+            age: None,
         };
 
-        let res = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire);
+        let sensor = Sensor {
+            strict: Some(true),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+
+        let res = wire_to_measurement(sensor, wire, Utc::now());
 
         match res {
             Err(Error(ErrorKind::InvalidValue(_), _)) => assert!(true),
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn wire_to_measurement_lenient() -> () {
+        let w_data_values: Vec<wire::DataValue> = vec![
+            wire::DataValue {
+                value_type: "SDS_P1".to_string(),
+                value: "7.87".to_string(),
+            },
+            wire::DataValue {
+                value_type: "this data type does not exists".to_string(),
+                value: "7.87".to_string(),
+            },
+            wire::DataValue {
+                value_type: "SDS_P2".to_string(),
+                value: "invalid float".to_string(),
+            },
+        ];
+        let wire = wire::Measurement {
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: w_data_values,
+            timestamp: None,
+            location: None,
+            age: None,
+        };
+
+        let m = wire_to_measurement(Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost"), wire, Utc::now()).unwrap();
+
+        assert_eq!(
+            m.data_values,
+            vec![
+                Value::SDS_P1(7.87f32),
+                Value::UNKNOWN("this data type does not exists".to_string(), "7.87".to_string()),
+                Value::UNKNOWN("SDS_P2".to_string(), "invalid float".to_string()),
+            ]
+        );
+        assert_eq!(m.warnings.len(), 2);
+    }
 }
 
 mod wire {
+    use serde::{Deserialize, Deserializer};
     use serde_json;
 
     error_chain! {
@@ -238,6 +817,10 @@ mod wire {
                 description("invalid measurement json")
                     display("invalid measurement json: '{}'", json)
             }
+            NoReadings {
+                description("no readings")
+                    display("api response contains no readings")
+            }
 
         }
     }
@@ -247,6 +830,24 @@ mod wire {
         pub software_version: String,
         #[serde(rename(deserialize = "sensordatavalues"))]
         pub data_values: Vec<DataValue>,
+        #[serde(default)]
+        pub timestamp: Option<String>,
+        #[serde(default)]
+        pub location: Option<Location>,
+        #[serde(default, deserialize_with = "string_or_number")]
+        pub age: Option<String>,
+    }
+
+    // Depending on the firmware version, some values are sent as strings or as numbers
+    fn string_or_number<'de, D>(deserializer: D) -> ::std::result::Result<Option<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value: Option<serde_json::Value> = Deserialize::deserialize(deserializer)?;
+        Ok(value.map(|v| match v {
+            serde_json::Value::String(s) => s,
+            v => v.to_string(),
+        }))
     }
 
     #[derive(Deserialize, Debug, PartialEq)]
@@ -255,14 +856,122 @@ mod wire {
         pub value: String,
     }
 
+    #[derive(Deserialize, Debug, PartialEq)]
+    pub struct Location {
+        pub latitude: String,
+        pub longitude: String,
+        pub altitude: Option<String>,
+        pub country: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct ApiReading {
+        timestamp: String,
+        location: Option<Location>,
+        sensor: Option<ApiSensor>,
+        sensordatavalues: Vec<DataValue>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct ApiSensor {
+        sensor_type: ApiSensorType,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct ApiSensorType {
+        name: String,
+    }
+
+    pub const API_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+    const MAX_JSON_IN_ERROR: usize = 100;
+
     pub fn decode_json_to_measurement<T: Into<String>>(json: T) -> Result<Measurement> {
         let json_string = json.into();
         let m: Measurement = serde_json::from_str(&json_string)
-            .chain_err(|| ErrorKind::InvalidJson(json_string))?;
+            .chain_err(|| ErrorKind::InvalidJson(truncate(&json_string, MAX_JSON_IN_ERROR)))?;
 
         Ok(m)
     }
 
+    pub fn decode_api_json_to_measurement<T: Into<String>>(json: T) -> Result<Measurement> {
+        let json_string = json.into();
+        let readings: Vec<ApiReading> = serde_json::from_str(&json_string)
+            .chain_err(|| ErrorKind::InvalidJson(truncate(&json_string, MAX_JSON_IN_ERROR)))?;
+        // Timestamps are formatted as '%Y-%m-%d %H:%M:%S', so the lexical order is the chronological order
+        let latest = match readings.into_iter().max_by(|a, b| a.timestamp.cmp(&b.timestamp)) {
+            Some(latest) => latest,
+            None => bail!(ErrorKind::NoReadings),
+        };
+        let prefix = api_value_type_prefix(latest.sensor.as_ref().map(|s| s.sensor_type.name.as_str()));
+        let data_values = latest
+            .sensordatavalues
+            .into_iter()
+            .filter_map(|dv| {
+                api_value_type(prefix, &dv.value_type).map(|value_type| DataValue {
+                    value_type: value_type,
+                    value: dv.value,
+                })
+            })
+            .collect();
+
+        Ok(Measurement {
+            software_version: String::new(),
+            data_values: data_values,
+            timestamp: Some(latest.timestamp),
+            location: latest.location,
+            age: None,
+        })
+    }
+
+    // The API reports value types without the firmware's sensor prefix, e.g., 'P1' or 'temperature'; the DHT22 values
+    // have no prefix in the firmware either
+    const API_SENSOR_PREFIXES: &[(&str, &str)] = &[
+        ("SDS", "SDS"),
+        ("PMS", "PMS"),
+        ("HPM", "HPM"),
+        ("SPS30", "SPS30"),
+        ("NEXTPM", "NPM"),
+        ("BME280", "BME280"),
+        ("BMP280", "BMP280"),
+        ("BMP180", "BMP"),
+        ("BMP085", "BMP"),
+        ("SHT3", "SHT3X"),
+        ("HTU21D", "HTU21D"),
+        ("DS18B20", "DS18B20"),
+        ("SCD30", "SCD30"),
+        ("DNMS", "DNMS"),
+        ("NEO-6M", "GPS"),
+    ];
+
+    fn api_value_type_prefix(sensor_type: Option<&str>) -> Option<&'static str> {
+        let sensor_type = sensor_type.unwrap_or("").to_uppercase();
+        API_SENSOR_PREFIXES
+            .iter()
+            .find(|&&(name, _)| sensor_type.starts_with(name))
+            .map(|&(_, prefix)| prefix)
+    }
+
+    // Particulates of unknown sensor types are taken to be from an SDS011; the pressure at sea level is derived by the
+    // API from the station's altitude rather than measured, so it is dropped
+    fn api_value_type(prefix: Option<&str>, value_type: &str) -> Option<String> {
+        match (prefix, value_type) {
+            (_, "pressure_at_sealevel") => None,
+            (Some(prefix), _) => Some(format!("{}_{}", prefix, value_type)),
+            (None, "P0") | (None, "P1") | (None, "P2") | (None, "P4") => Some(format!("SDS_{}", value_type)),
+            (None, _) => Some(value_type.to_string()),
+        }
+    }
+
+    fn truncate(s: &str, max_chars: usize) -> String {
+        if s.chars().count() <= max_chars {
+            s.to_string()
+        } else {
+            let truncated: String = s.chars().take(max_chars).collect();
+            format!("{}...", truncated)
+        }
+    }
+
 
     #[cfg(test)]
     mod test {
@@ -308,6 +1017,9 @@ mod wire {
             let expected = Measurement {
                 software_version: "NRZ-2017-089".to_string(),
                 data_values: data_values,
+                timestamp: None,
+                location: None,
+                age: None,
             };
 
             let res = decode_json_to_measurement(json);
@@ -315,6 +1027,98 @@ mod wire {
             assert_eq!(expected, res.unwrap());
         }
 
+        #[test]
+        fn decode_api_json_to_measurement_ok() -> () {
+            let json = r#"[
+                {"id":1,"sampling_rate":null,"timestamp":"2018-01-24 12:00:03","location":{"id":7,"latitude":"48.800","longitude":"9.200","altitude":"250.2","country":"DE","exact_location":0,"indoor":0},"sensor":{"id":12345,"pin":"1","sensor_type":{"id":14,"name":"SDS011","manufacturer":"Nova Fitness"}},"sensordatavalues":[{"id":2,"value":"12.30","value_type":"P1"},{"id":3,"value":"5.10","value_type":"P2"}]},
+                {"id":4,"sampling_rate":null,"timestamp":"2018-01-24 11:57:30","location":{"id":7,"latitude":"48.800","longitude":"9.200","altitude":"250.2","country":"DE","exact_location":0,"indoor":0},"sensor":{"id":12345,"pin":"1","sensor_type":{"id":14,"name":"SDS011","manufacturer":"Nova Fitness"}},"sensordatavalues":[{"id":5,"value":"11.00","value_type":"P1"},{"id":6,"value":"4.00","value_type":"P2"}]}
+            ]"#;
+            let expected = Measurement {
+                software_version: String::new(),
+                data_values: vec![
+                    DataValue {
+                        value_type: "SDS_P1".to_string(),
+                        value: "12.30".to_string(),
+                    },
+                    DataValue {
+                        value_type: "SDS_P2".to_string(),
+                        value: "5.10".to_string(),
+                    },
+                ],
+                timestamp: Some("2018-01-24 12:00:03".to_string()),
+                location: Some(Location {
+                    latitude: "48.800".to_string(),
+                    longitude: "9.200".to_string(),
+                    altitude: Some("250.2".to_string()),
+                    country: Some("DE".to_string()),
+                }),
+                age: None,
+            };
+
+            let res = decode_api_json_to_measurement(json);
+
+            assert_eq!(expected, res.unwrap());
+        }
+
+        #[test]
+        fn decode_api_json_to_measurement_sensor_type() -> () {
+            let json = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12345,"pin":"1","sensor_type":{"id":19,"name":"PMS5003","manufacturer":"Plantower"}},"sensordatavalues":[{"id":2,"value":"3.00","value_type":"P0"},{"id":3,"value":"12.30","value_type":"P1"}]}]"#;
+
+            let res = decode_api_json_to_measurement(json).unwrap();
+
+            let value_types: Vec<_> = res.data_values.iter().map(|dv| dv.value_type.as_str()).collect();
+            assert_eq!(value_types, vec!["PMS_P0", "PMS_P1"]);
+        }
+
+        #[test]
+        fn decode_api_json_to_measurement_bme280() -> () {
+            let json = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12346,"pin":"11","sensor_type":{"id":17,"name":"BME280","manufacturer":"Bosch"}},"sensordatavalues":[{"id":2,"value":"3.40","value_type":"temperature"},{"id":3,"value":"81.20","value_type":"humidity"},{"id":4,"value":"98250.43","value_type":"pressure"},{"id":5,"value":"101330.12","value_type":"pressure_at_sealevel"}]}]"#;
+
+            let res = decode_api_json_to_measurement(json).unwrap();
+
+            let value_types: Vec<_> = res.data_values.iter().map(|dv| dv.value_type.as_str()).collect();
+            assert_eq!(value_types, vec!["BME280_temperature", "BME280_humidity", "BME280_pressure"]);
+        }
+
+        #[test]
+        fn decode_api_json_to_measurement_dnms() -> () {
+            let json = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12347,"pin":"15","sensor_type":{"id":29,"name":"DNMS (Laerm)","manufacturer":"Luftdaten.info"}},"sensordatavalues":[{"id":2,"value":"52.30","value_type":"noise_LAeq"},{"id":3,"value":"41.10","value_type":"noise_LA_min"},{"id":4,"value":"67.80","value_type":"noise_LA_max"}]}]"#;
+
+            let res = decode_api_json_to_measurement(json).unwrap();
+
+            let value_types: Vec<_> = res.data_values.iter().map(|dv| dv.value_type.as_str()).collect();
+            assert_eq!(value_types, vec!["DNMS_noise_LAeq", "DNMS_noise_LA_min", "DNMS_noise_LA_max"]);
+        }
+
+        #[test]
+        fn decode_api_json_to_measurement_dht22() -> () {
+            let json = r#"[{"id":1,"timestamp":"2018-01-24 12:00:03","location":null,"sensor":{"id":12348,"pin":"7","sensor_type":{"id":9,"name":"DHT22","manufacturer":"various"}},"sensordatavalues":[{"id":2,"value":"3.40","value_type":"temperature"},{"id":3,"value":"81.20","value_type":"humidity"}]}]"#;
+
+            let res = decode_api_json_to_measurement(json).unwrap();
+
+            let value_types: Vec<_> = res.data_values.iter().map(|dv| dv.value_type.as_str()).collect();
+            assert_eq!(value_types, vec!["temperature", "humidity"]);
+        }
+
+        #[test]
+        fn decode_api_json_to_measurement_no_readings() -> () {
+            let res = decode_api_json_to_measurement("[]");
+
+            match res {
+                Err(Error(ErrorKind::NoReadings, _)) => assert!(true),
+                _ => assert!(false),
+            }
+        }
+
+        #[test]
+        fn decode_json_to_measurement_age() -> () {
+            let json = r#"{"software_version": "NRZ-2017-089", "age":"37", "sensordatavalues":[]}"#;
+
+            let res = decode_json_to_measurement(json);
+
+            assert_eq!(res.unwrap().age, Some("37".to_string()));
+        }
+
         #[test]
         fn decode_json_to_measurement_invalid_json() -> () {
             let json = r#"{some invalid stuff"#;
@@ -326,5 +1130,20 @@ mod wire {
                 _ => assert!(false),
             }
         }
+
+        #[test]
+        fn decode_json_to_measurement_truncates_invalid_json() -> () {
+            let html = format!("<html><body>{}</body></html>", "Please log in to the captive portal. ".repeat(20));
+
+            let res = decode_json_to_measurement(html);
+
+            match res {
+                Err(Error(ErrorKind::InvalidJson(json), _)) => {
+                    assert_eq!(json.chars().count(), MAX_JSON_IN_ERROR + 3);
+                    assert!(json.ends_with("..."));
+                }
+                _ => assert!(false),
+            }
+        }
     }
 }
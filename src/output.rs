@@ -1,4 +1,9 @@
+use check::CheckedMeasurement;
+use drift::Drift;
+use group::{Aggregation, CheckedGroup};
 use measurement::{Measurement, Value};
+use sensor::{FailedMeasurement, Sensor};
+use state::Exceedances;
 use std::io::Write;
 use tabwriter::TabWriter;
 
@@ -8,15 +13,8 @@ pub fn print_measurements(measurements: &[&Measurement]) -> () {
         let values_str = m.data_values
             .iter()
             .map(|value| match *value {
-                Value::SDS_P1(v) => format!("PM 10: {}", v),
-                Value::SDS_P2(v) => format!("PM 2.5: {}", v),
-                Value::TEMPERATURE(v) => format!("Temperature: {}", v),
-                Value::HUMIDITY(v) => format!("Humidity: {}", v),
-                Value::SAMPLES(v) => format!("Samples: {}", v),
-                Value::MIN_MICRO(v) => format!("Min. micro: {}", v),
-                Value::MAX_MICRO(v) => format!("Max. micro: {}", v),
-                Value::SIGNAL(v) => format!("Wifi signal: {}", v),
-                Value::UNKNOWN(ref s) => format!("Unknown value: {}", s),
+                Value::UNKNOWN(ref name, ref v) => format!("{}: {}", name, v),
+                ref value => format!("{}: {}", value.name(), value.value().unwrap_or(0.0)),
             })
             .collect::<Vec<_>>()
             .join("\t");
@@ -33,3 +31,276 @@ pub fn print_measurements(measurements: &[&Measurement]) -> () {
 
     println!("{}", out_str);
 }
+
+pub fn print_exceedances(exceedances: &[(&Sensor, Exceedances)]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for &(sensor, ref e) in exceedances {
+        let max_str = sensor.max_exceedance_days
+            .map(|max| format!(" of {} allowed", max))
+            .unwrap_or_else(String::new);
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}:\t{} days{} with PM 10 daily mean above {} µg/m³\t({} days with data)",
+            sensor.name,
+            sensor.id,
+            e.year,
+            e.days,
+            max_str,
+            e.limit,
+            e.days_with_data
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_means(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        let means_str = cm.means
+            .iter()
+            .map(|m| format!("{} {} mean: {} ({} samples)", m.value, m.window, m.mean, m.samples))
+            .collect::<Vec<_>>()
+            .join("\t");
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}",
+            cm.measurement.sensor.name,
+            cm.measurement.sensor.id,
+            means_str
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_humidity(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        let particulates = [("PM 10", cm.pm10, cm.raw_pm10), ("PM 2.5", cm.pm2, cm.raw_pm2), ("PM 1", cm.pm1, cm.raw_pm1)];
+        let mut values = particulates
+            .iter()
+            .filter_map(|&(name, pm, raw)| match (pm, raw) {
+                (Some(pm), Some(raw)) if cm.humidity_corrected => Some(format!("{}: {} (raw {})", name, pm, raw)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if cm.humidity_suppressed {
+            values.push("particulates not checked".to_string());
+        }
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\thumidity {}%:\t{}",
+            cm.measurement.sensor.name,
+            cm.measurement.sensor.id,
+            cm.humidity.unwrap_or(0.0),
+            values.join("\t")
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_aqi(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        let indices = [("US AQI", cm.us_aqi.as_ref()), ("CAQI", cm.caqi.as_ref())];
+        let index_str = indices
+            .iter()
+            .filter_map(|&(name, index)| index.map(|i| {
+                let nowcast = if i.nowcast { ", NowCast" } else { "" };
+                format!("{}: {} {} ({}{})", name, i.value, i.category, i.pollutant, nowcast)
+            }))
+            .collect::<Vec<_>>()
+            .join("\t");
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}",
+            cm.measurement.sensor.name,
+            cm.measurement.sensor.id,
+            index_str
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_rates(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        let rates_str = cm.rates
+            .iter()
+            .map(|r| format!("{} change within {}: {:+} (from {})", r.value, r.window, r.delta, r.reference))
+            .collect::<Vec<_>>()
+            .join("\t");
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}",
+            cm.measurement.sensor.name,
+            cm.measurement.sensor.id,
+            rates_str
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_violations(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        for v in &cm.violations {
+            let _ = writeln!(
+                &mut tw,
+                "{}\t({})\t{}\t{}:\t{} {} {}",
+                cm.measurement.sensor.name,
+                cm.measurement.sensor.id,
+                v.severity,
+                v.rule,
+                v.measured,
+                v.direction,
+                v.limit
+            );
+        }
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_groups(checked_groups: &[&CheckedGroup]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cg in checked_groups {
+        let aggregation = match cg.group.aggregation {
+            Some(Aggregation::Median) => "median of".to_string(),
+            Some(Aggregation::Max) => "max of".to_string(),
+            Some(Aggregation::AtLeast { count }) => format!("at least {} of", count),
+            _ => "mean of".to_string(),
+        };
+        let cm = &cg.checked_measurement;
+        let particulates = [("PM 10", cm.pm10), ("PM 2.5", cm.pm2), ("PM 1", cm.pm1)];
+        let values_str = particulates
+            .iter()
+            .filter_map(|&(name, v)| v.map(|v| format!("{}: {}", name, v)))
+            .collect::<Vec<_>>()
+            .join("\t");
+        let violations_str = cm.violations
+            .iter()
+            .map(|v| format!("{} {}: {} {} {}", v.severity, v.rule, v.measured, v.direction, v.limit))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{} {} sensors:\t{}\t{}",
+            cg.group.name,
+            cg.group.id,
+            aggregation,
+            cg.members.len(),
+            values_str,
+            violations_str
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_drifts(drifts: &[&Drift]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for d in drifts {
+        let readings_str = d.readings
+            .iter()
+            .map(|r| match r.mean {
+                Some(mean) => format!("{}: {:.2} (mean {:.2})", r.name, r.value, mean),
+                None => format!("{}: {:.2}", r.name, r.value),
+            })
+            .collect::<Vec<_>>()
+            .join("\t");
+        let ratio_str = d.ratio.map(|r| format!("ratio {:.2}", r)).unwrap_or_else(|| "ratio -".to_string());
+        let means_str = match (d.mean_difference, d.mean_ratio) {
+            (Some(difference), Some(ratio)) => format!("\tmean difference {:.2}\tmean ratio {:.2}", difference, ratio),
+            (Some(difference), None) => format!("\tmean difference {:.2}\tmean ratio -", difference),
+            _ => String::new(),
+        };
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}:\t{}\tdifference {:.2}\t{}{}\t{}",
+            d.colocation.name,
+            d.colocation.id,
+            d.value,
+            readings_str,
+            d.difference,
+            ratio_str,
+            means_str,
+            if d.drifting { "drifting" } else { "ok" }
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_suspects(checked_measurements: &[&CheckedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for cm in checked_measurements {
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({}):\t{}",
+            cm.measurement.sensor.name,
+            cm.measurement.sensor.id,
+            cm.suspect_reasons.join(", ")
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_warnings(measurements: &[&Measurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for m in measurements {
+        for w in &m.warnings {
+            let _ = writeln!(
+                &mut tw,
+                "{}\t({})\t{}",
+                m.sensor.name,
+                m.sensor.id,
+                w
+            );
+        }
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
+
+pub fn print_failures(failures: &[&FailedMeasurement]) -> () {
+    let mut tw = TabWriter::new(vec![]);
+    for f in failures {
+        let _ = writeln!(
+            &mut tw,
+            "{}\t({})\t{}",
+            f.sensor.name,
+            f.sensor.id,
+            f.error
+        );
+    }
+    tw.flush().unwrap();
+    let out_str = String::from_utf8(tw.into_inner().unwrap()).unwrap();
+
+    println!("{}", out_str);
+}
@@ -0,0 +1,74 @@
+use config::DEFAULT_INTERVAL;
+use sensor::{Sensor, SensorId};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Each sensor is polled according to its own interval; sensors never polled before are due immediately
+pub struct Schedule {
+    next_polls: HashMap<SensorId, Instant>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule { next_polls: HashMap::new() }
+    }
+
+    // Returns the sensors due at 'now' and schedules their next polls
+    pub fn due_sensors(&mut self, sensors: &[Sensor], now: Instant) -> Vec<Sensor> {
+        let due_sensors = sensors
+            .iter()
+            .filter(|s| self.next_polls.get(&s.id).map(|next| *next <= now).unwrap_or(true))
+            .cloned()
+            .collect::<Vec<_>>();
+        for s in &due_sensors {
+            let interval = s.interval.unwrap_or(DEFAULT_INTERVAL);
+            self.next_polls.insert(s.id.clone(), now + Duration::from_secs(interval));
+        }
+
+        due_sensors
+    }
+
+    pub fn next_poll(&self, now: Instant) -> Instant {
+        self.next_polls.values().min().cloned().unwrap_or_else(|| now + Duration::from_secs(DEFAULT_INTERVAL))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sensor(id: &str, interval: u64) -> Sensor {
+        Sensor {
+            interval: Some(interval),
+            ..Sensor::new(id, id, "http://localhost", "http://localhost")
+        }
+    }
+
+    #[test]
+    fn due_sensors_okay() -> () {
+        let sensors = vec![sensor("1", 60), sensor("2", 150)];
+        let mut schedule = Schedule::new();
+        let start = Instant::now();
+
+        assert_eq!(schedule.due_sensors(&sensors, start).len(), 2);
+        assert!(schedule.due_sensors(&sensors, start + Duration::from_secs(30)).is_empty());
+        assert_eq!(schedule.next_poll(start), start + Duration::from_secs(60));
+
+        let due = schedule.due_sensors(&sensors, start + Duration::from_secs(60));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, "1");
+        assert_eq!(schedule.next_poll(start), start + Duration::from_secs(120));
+
+        let due = schedule.due_sensors(&sensors, start + Duration::from_secs(150));
+        assert_eq!(due.iter().map(|s| &s.id[..]).collect::<Vec<_>>(), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn next_poll_without_sensors() -> () {
+        let schedule = Schedule::new();
+        let now = Instant::now();
+
+        assert_eq!(schedule.next_poll(now), now + Duration::from_secs(DEFAULT_INTERVAL));
+    }
+}
@@ -1,14 +1,48 @@
+use check::{HumidityCorrection, PlausibleRange, Rule, Threshold};
 use config;
-use measurement::{self, Measurement};
-use futures::{Future, Stream};
-use hyper::Client;
-use hyper::client::{FutureResponse, HttpConnector};
-use std::str;
-use tokio_core::reactor::Core;
+use measurement;
+use source::Source;
+
+use hyper::Uri;
 
 error_chain! {
     errors {
-
+        Timeout(uri: String) {
+            description("timed out")
+            display("timed out while reading from '{}'", uri)
+        }
+        ConnectionRefused(uri: String) {
+            description("connection refused")
+            display("connection refused by '{}'", uri)
+        }
+        HttpStatus(code: u16, uri: String) {
+            description("http error status")
+            display("http error status {} from '{}'", code, uri)
+        }
+        TooManyRedirects(uri: String) {
+            description("too many redirects")
+            display("too many redirects while reading from '{}'", uri)
+        }
+        InvalidRedirect(location: String, uri: String) {
+            description("invalid redirect")
+            display("invalid redirect to '{}' from '{}'", location, uri)
+        }
+        UnexpectedContentType(content_type: String, uri: String) {
+            description("unexpected content type")
+            display("unexpected content type '{}' from '{}'", content_type, uri)
+        }
+        InvalidCaFile(ca_file: String) {
+            description("invalid ca file")
+            display("invalid ca file '{}'", ca_file)
+        }
+        CouldNotReadFile(path: String) {
+            description("could not read file")
+            display("could not read measurement from file '{}'", path)
+        }
+        NoDataSource(sensor: String) {
+            description("no data source")
+            display("neither source, data_uri nor api_id set for sensor '{}'", sensor)
+        }
     }
     links {
         ReadingMeasurementFailed(measurement::Error, measurement::ErrorKind);
@@ -18,21 +52,54 @@ error_chain! {
         Io(::std::io::Error);
         Hyper(::hyper::Error);
 		CouldNotParse(::serde_json::Error);
+        InvalidUri(::hyper::error::UriError);
+        Tls(::native_tls::Error);
     }
 }
 
 pub type SensorId = String;
 
 #[derive(Debug, Deserialize, Serialize)]
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Sensor {
     pub name: String,
     pub id: SensorId,
     pub ui_uri: String,
-    pub data_uri: String,
-    pub threshold_pm10: Option<f32>,
-    pub threshold_pm2: Option<f32>,
+    pub source: Option<Source>,
+    pub data_uri: Option<String>,
+    pub api_id: Option<String>,
+    pub api_uri: Option<String>,
+    pub threshold_pm10: Option<Threshold>,
+    pub threshold_pm2: Option<Threshold>,
+    pub threshold_pm1: Option<Threshold>,
+    pub rules: Option<Vec<Rule>>,
+    pub clear_margin: Option<f32>,
+    pub hold_readings: Option<u32>,
     pub notification_condition: Option<config::NotificationCondition>,
+    pub interval: Option<u64>,
+    pub notify_on_failure: Option<bool>,
+    pub unreachable_after_failures: Option<u32>,
+    pub unreachable_after: Option<u64>,
+    pub max_age: Option<u64>,
+    pub exceedance_limit: Option<f32>,
+    pub max_exceedance_days: Option<u32>,
+    pub humidity_correction: Option<HumidityCorrection>,
+    pub suppress_above_humidity: Option<f32>,
+    pub plausible_ranges: Option<Vec<PlausibleRange>>,
+    pub stuck_readings: Option<u32>,
+    pub notify_on_suspect: Option<bool>,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub max_redirects: Option<u32>,
+    pub ca_file: Option<String>,
+    pub verify_certificate: Option<bool>,
+    pub strict: Option<bool>,
+    // The data URI of an http or api source as validated during config parsing
+    #[serde(skip)]
+    pub parsed_data_uri: Option<Uri>,
 }
 
 impl Sensor {
@@ -41,30 +108,63 @@ impl Sensor {
             name: name.into(),
             id: id.into(),
             ui_uri: ui_uri.into(),
-            data_uri: data_uri.into(),
+            source: None,
+            data_uri: Some(data_uri.into()),
+            api_id: None,
+            api_uri: None,
             threshold_pm10: None,
             threshold_pm2: None,
+            threshold_pm1: None,
+            rules: None,
+            clear_margin: None,
+            hold_readings: None,
             notification_condition: None,
+            interval: None,
+            notify_on_failure: None,
+            unreachable_after_failures: None,
+            unreachable_after: None,
+            max_age: None,
+            exceedance_limit: None,
+            max_exceedance_days: None,
+            humidity_correction: None,
+            suppress_above_humidity: None,
+            plausible_ranges: None,
+            stuck_readings: None,
+            notify_on_suspect: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retries: None,
+            retry_backoff: None,
+            max_redirects: None,
+            ca_file: None,
+            verify_certificate: None,
+            strict: None,
+            parsed_data_uri: None,
         }
     }
 
-    pub fn read_measurement(
-        self: Self,
-        response: FutureResponse,
-    ) -> Box<Future<Item = Measurement, Error = Error>> {
-        let m = response
-            .and_then(|res| res.body().concat2())
-            .map(|body| {
-                let json = str::from_utf8(&body)?;
-                Measurement::from_json(self, json).map_err(|e| e.into())
-            })
-            .map_err(|e| e.into())
-            .and_then(|x| x);
-        Box::new(m)
+    // 'data_uri' and 'api_id' are shorthands for an http and an api source, respectively
+    pub fn source(&self) -> Option<Source> {
+        match (&self.source, &self.data_uri, &self.api_id) {
+            (&Some(ref source), _, _) => Some(source.clone()),
+            (&None, &Some(ref data_uri), _) => Some(Source::Http { uri: data_uri.clone() }),
+            (&None, &None, &Some(ref api_id)) => Some(Source::Api { id: api_id.clone(), uri: None }),
+            (&None, &None, &None) => None,
+        }
     }
+}
 
+#[derive(Debug, Serialize)]
+pub struct FailedMeasurement {
+    pub sensor: Sensor,
+    pub error: String,
 }
 
-pub fn create_sensor_reader(core: &mut Core) -> Client<HttpConnector> {
-    Client::new(&core.handle())
+impl FailedMeasurement {
+    pub fn new(sensor: Sensor, error: &Error) -> FailedMeasurement {
+        let mut messages = error.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        messages.dedup();
+        let error = messages.join(": ");
+        FailedMeasurement { sensor: sensor, error: error }
+    }
 }
@@ -0,0 +1,536 @@
+use config;
+use connector::{HttpsConnector, TimeoutConnector};
+use futures::{future, Future, IntoFuture, Stream};
+use futures::future::{Loop, loop_fn};
+use hyper::{self, Chunk, Client, Response, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{ContentType, Location};
+use measurement::Measurement;
+use native_tls;
+use sensor::{Error, ErrorKind, Result, ResultExt, Sensor};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind")]
+#[derive(PartialEq, Clone)]
+pub enum Source {
+    Http { uri: String },
+    Api { id: String, uri: Option<String> },
+    File { path: String },
+    Stdin,
+}
+
+/// Reads measurements for a sensor; implement this trait to plug in additional sources.
+pub trait MeasurementSource {
+    fn read_measurement(&self, sensor: Sensor, handle: &Handle) -> Box<Future<Item = Measurement, Error = Error>>;
+}
+
+pub struct HttpSource {
+    client: SensorClient,
+    uri: Uri,
+}
+
+impl HttpSource {
+    pub fn new(client: SensorClient, uri: Uri) -> HttpSource {
+        HttpSource {
+            client: client,
+            uri: uri,
+        }
+    }
+
+    fn read_body(&self, sensor: &Sensor, handle: &Handle) -> Box<Future<Item = Chunk, Error = Error>> {
+        let uri = self.uri.clone();
+        let read_timeout = Duration::from_secs(sensor.read_timeout.unwrap_or(config::DEFAULT_READ_TIMEOUT));
+        let retries = sensor.retries.unwrap_or(config::DEFAULT_RETRIES);
+        let retry_backoff = Duration::from_secs(sensor.retry_backoff.unwrap_or(config::DEFAULT_RETRY_BACKOFF));
+        let max_redirects = sensor.max_redirects.unwrap_or(config::DEFAULT_MAX_REDIRECTS);
+
+        let client = self.client.clone();
+        let fetch_handle = handle.clone();
+        let max_backoff = Duration::from_secs(config::MAX_RETRY_BACKOFF);
+        retry(handle, retries, retry_backoff, max_backoff, move || {
+            fetch_body(&client, &fetch_handle, uri.clone(), read_timeout, max_redirects)
+        })
+    }
+}
+
+impl MeasurementSource for HttpSource {
+    fn read_measurement(&self, sensor: Sensor, handle: &Handle) -> Box<Future<Item = Measurement, Error = Error>> {
+        let m = self.read_body(&sensor, handle)
+            .and_then(move |body| {
+                let json = str::from_utf8(&body)?;
+                Measurement::from_json(sensor, json).map_err(|e| e.into())
+            });
+        Box::new(m)
+    }
+}
+
+pub struct ApiSource {
+    http: HttpSource,
+}
+
+impl ApiSource {
+    pub fn new(client: SensorClient, data_uri: Uri) -> ApiSource {
+        ApiSource {
+            http: HttpSource::new(client, data_uri),
+        }
+    }
+}
+
+impl MeasurementSource for ApiSource {
+    fn read_measurement(&self, sensor: Sensor, handle: &Handle) -> Box<Future<Item = Measurement, Error = Error>> {
+        let m = self.http.read_body(&sensor, handle)
+            .and_then(move |body| {
+                let json = str::from_utf8(&body)?;
+                Measurement::from_api_json(sensor, json).map_err(|e| e.into())
+            });
+        Box::new(m)
+    }
+}
+
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new<T: Into<PathBuf>>(path: T) -> FileSource {
+        FileSource { path: path.into() }
+    }
+}
+
+impl MeasurementSource for FileSource {
+    fn read_measurement(&self, sensor: Sensor, _: &Handle) -> Box<Future<Item = Measurement, Error = Error>> {
+        let path = self.path.to_string_lossy().to_string();
+        let m = read_file(&self.path)
+            .chain_err(|| ErrorKind::CouldNotReadFile(path))
+            .and_then(|json| Measurement::from_json(sensor, &json).map_err(|e| e.into()));
+        Box::new(future::result(m))
+    }
+}
+
+// Stdin can only be read once, so this source is meant for single runs, e.g., for piping a data.json into luftpost;
+// it is read when the source is created to keep blocking I/O off the reactor
+pub struct StdinSource {
+    json: String,
+}
+
+impl StdinSource {
+    pub fn new<R: Read>(mut reader: R) -> Result<StdinSource> {
+        let mut json = String::new();
+        reader.read_to_string(&mut json)?;
+        Ok(StdinSource { json: json })
+    }
+}
+
+impl MeasurementSource for StdinSource {
+    fn read_measurement(&self, sensor: Sensor, _: &Handle) -> Box<Future<Item = Measurement, Error = Error>> {
+        let m = Measurement::from_json(sensor, &self.json).map_err(|e| e.into());
+        Box::new(future::result(m))
+    }
+}
+
+pub type SensorClient = Client<TimeoutConnector<HttpsConnector<HttpConnector>>>;
+
+pub fn create_sensor_reader(handle: &Handle, sensor: &Sensor) -> Result<Box<MeasurementSource>> {
+    let source: Box<MeasurementSource> = match sensor.source() {
+        Some(Source::Http { uri }) => Box::new(HttpSource::new(create_client(handle, sensor)?, data_uri(sensor, &uri)?)),
+        Some(Source::Api { id, uri }) => {
+            let api_uri = uri.or_else(|| sensor.api_uri.clone());
+            let data_uri = data_uri(sensor, &api_data_uri(api_uri.as_ref().map(|x| x.as_str()), &id))?;
+            Box::new(ApiSource::new(create_client(handle, sensor)?, data_uri))
+        }
+        Some(Source::File { path }) => Box::new(FileSource::new(path)),
+        Some(Source::Stdin) => Box::new(StdinSource::new(io::stdin())?),
+        None => bail!(ErrorKind::NoDataSource(sensor.name.clone())),
+    };
+
+    Ok(source)
+}
+
+// Config parsing keeps the validated data URI, but sensors may be created programmatically
+fn data_uri(sensor: &Sensor, uri: &str) -> Result<Uri> {
+    match sensor.parsed_data_uri {
+        Some(ref parsed) => Ok(parsed.clone()),
+        None => uri.parse().map_err(|e: hyper::error::UriError| e.into()),
+    }
+}
+
+pub fn create_client(handle: &Handle, sensor: &Sensor) -> Result<SensorClient> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ref ca_file) = sensor.ca_file {
+        for cert in read_ca_file(ca_file).chain_err(|| ErrorKind::InvalidCaFile(ca_file.clone()))? {
+            builder.add_root_certificate(cert);
+        }
+    }
+    if !sensor.verify_certificate.unwrap_or(true) {
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+    let tls = builder.build()?;
+
+    let mut http = HttpConnector::new(1, handle);
+    http.enforce_http(false);
+    let https = HttpsConnector::new(http, tls);
+    let connect_timeout = Duration::from_secs(sensor.connect_timeout.unwrap_or(config::DEFAULT_CONNECT_TIMEOUT));
+    let connector = TimeoutConnector::new(https, connect_timeout, handle);
+
+    Ok(Client::configure().connector(connector).build(handle))
+}
+
+fn read_ca_file<P: AsRef<Path>>(ca_file: P) -> Result<Vec<native_tls::Certificate>> {
+    let mut file = File::open(ca_file)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+
+    let mut certs = Vec::new();
+    for pem in split_pem_certificates(&content) {
+        certs.push(native_tls::Certificate::from_pem(pem.as_bytes())?);
+    }
+    if certs.is_empty() {
+        bail!("no certificate found");
+    }
+
+    Ok(certs)
+}
+
+// A bundle holds any number of certificates, possibly separated by comments
+fn split_pem_certificates(content: &str) -> Vec<String> {
+    content
+        .split_terminator(PEM_END_CERTIFICATE)
+        .filter_map(|pem| pem.find(PEM_BEGIN_CERTIFICATE).map(|begin| format!("{}{}", &pem[begin..], PEM_END_CERTIFICATE)))
+        .collect()
+}
+
+const PEM_BEGIN_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+const PEM_END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+pub fn api_data_uri(api_uri: Option<&str>, api_id: &str) -> String {
+    let api_uri = api_uri.unwrap_or(config::DEFAULT_API_URI);
+    format!("{}/{}/", api_uri.trim_right_matches('/'), api_id)
+}
+
+fn fetch_body(
+    client: &SensorClient,
+    handle: &Handle,
+    uri: Uri,
+    timeout: Duration,
+    max_redirects: u32,
+) -> Box<Future<Item = Chunk, Error = Error>> {
+    let timeout_uri_str = uri.to_string();
+    let client = client.clone();
+
+    let request = loop_fn((uri, 0), move |(uri, redirects)| {
+        let uri_str = uri.to_string();
+        let error_uri_str = uri_str.clone();
+        client
+            .get(uri.clone())
+            .map_err(move |e| classify_hyper_error(e, error_uri_str))
+            .and_then(move |res| {
+                let status = res.status();
+                if is_followed_redirect(status.as_u16()) {
+                    if redirects >= max_redirects {
+                        bail!(ErrorKind::TooManyRedirects(uri_str));
+                    }
+                    let next_uri = redirect_uri(&uri, &res)?;
+                    Ok(Loop::Continue((next_uri, redirects + 1)))
+                } else if status.is_success() {
+                    check_content_type(&res, &uri_str)?;
+                    Ok(Loop::Break(res))
+                } else {
+                    Err(ErrorKind::HttpStatus(status.as_u16(), uri_str).into())
+                }
+            })
+    }).and_then(|res| res.body().concat2().map_err(|e| e.into()));
+    let timeout = Timeout::new(timeout, handle)
+        .into_future()
+        .flatten()
+        .map_err(|e| e.into())
+        .and_then(move |_| Err(ErrorKind::Timeout(timeout_uri_str).into()));
+
+    let body = request
+        .select(timeout)
+        .map(|(body, _)| body)
+        .map_err(|(e, _)| e);
+    Box::new(body)
+}
+
+// Other 3xx statuses like 304 Not Modified are no redirects to follow and thus reported as http errors
+fn is_followed_redirect(status: u16) -> bool {
+    match status {
+        301 | 302 | 303 | 307 | 308 => true,
+        _ => false,
+    }
+}
+
+fn redirect_uri(uri: &Uri, res: &Response) -> Result<Uri> {
+    let location = match res.headers().get::<Location>() {
+        Some(location) => location.to_string(),
+        None => bail!(ErrorKind::InvalidRedirect(String::new(), uri.to_string())),
+    };
+
+    resolve_redirect(uri, &location)
+}
+
+fn resolve_redirect(uri: &Uri, location: &str) -> Result<Uri> {
+    let absolute = match (location.starts_with('/'), uri.scheme(), uri.authority()) {
+        (false, _, _) => location.to_string(),
+        (true, Some(scheme), Some(authority)) => format!("{}://{}{}", scheme, authority, location),
+        _ => bail!(ErrorKind::InvalidRedirect(location.to_string(), uri.to_string())),
+    };
+
+    match absolute.parse::<Uri>() {
+        Ok(ref next) if next.scheme().is_some() && next.authority().is_some() => Ok(next.clone()),
+        _ => bail!(ErrorKind::InvalidRedirect(location.to_string(), uri.to_string())),
+    }
+}
+
+fn check_content_type(res: &Response, uri: &str) -> Result<()> {
+    if let Some(content_type) = res.headers().get::<ContentType>() {
+        let content_type = content_type.to_string();
+        let mime = content_type.to_lowercase();
+        if !(mime.contains("json") || mime.starts_with("text/plain")) {
+            bail!(ErrorKind::UnexpectedContentType(content_type, uri.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn classify_hyper_error(error: hyper::Error, uri: String) -> Error {
+    let kind = match error {
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Some(ErrorKind::ConnectionRefused(uri)),
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::TimedOut => Some(ErrorKind::Timeout(uri)),
+        hyper::Error::Timeout => Some(ErrorKind::Timeout(uri)),
+        _ => None,
+    };
+    match kind {
+        Some(kind) => Error::with_chain(error, kind),
+        None => error.into(),
+    }
+}
+
+// Only connection errors, timeouts, and server errors may go away by retrying
+fn is_transient(error: &Error) -> bool {
+    match *error.kind() {
+        ErrorKind::ConnectionRefused(_) | ErrorKind::Timeout(_) | ErrorKind::Io(_) => true,
+        ErrorKind::HttpStatus(code, _) => code >= 500,
+        ErrorKind::Hyper(hyper::Error::Io(_)) => true,
+        _ => false,
+    }
+}
+
+// The delay doubles with each retry up to 'max_backoff'
+fn retry<T, F>(handle: &Handle, retries: u32, backoff: Duration, max_backoff: Duration, f: F) -> Box<Future<Item = T, Error = Error>>
+where
+    T: 'static,
+    F: Fn() -> Box<Future<Item = T, Error = Error>> + 'static,
+{
+    let handle = handle.clone();
+    let r = loop_fn((0, backoff), move |(attempt, delay)| {
+        let handle = handle.clone();
+        f().then(move |res| -> Box<Future<Item = Loop<T, (u32, Duration)>, Error = Error>> {
+            match res {
+                Ok(value) => Box::new(future::ok(Loop::Break(value))),
+                Err(ref e) if attempt < retries && is_transient(e) => {
+                    let wait = Timeout::new(delay, &handle)
+                        .into_future()
+                        .flatten()
+                        .map(move |_| Loop::Continue((attempt + 1, next_backoff(delay, max_backoff))))
+                        .map_err(|e| e.into());
+                    Box::new(wait)
+                }
+                Err(e) => Box::new(future::err(e)),
+            }
+        })
+    });
+    Box::new(r)
+}
+
+fn next_backoff(delay: Duration, max_backoff: Duration) -> Duration {
+    ::std::cmp::min(delay * 2, max_backoff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn api_data_uri_ok() -> () {
+        assert_eq!(api_data_uri(None, "12345"), "https://api.luftdaten.info/v1/sensor/12345/");
+        assert_eq!(api_data_uri(Some("http://localhost:8080/v1/sensor"), "12345"), "http://localhost:8080/v1/sensor/12345/");
+    }
+
+    #[test]
+    fn split_pem_certificates_okay() -> () {
+        let bundle = "# Root CA\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n\n\
+                      # Intermediate CA\n-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n";
+
+        let res = split_pem_certificates(bundle);
+
+        assert_eq!(res, vec![
+            "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----".to_string(),
+            "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----".to_string(),
+        ]);
+        assert!(split_pem_certificates("no certificates").is_empty());
+    }
+
+    #[test]
+    fn stdin_source_reads_once() -> () {
+        let mut core = Core::new().unwrap();
+        let json = r#"{"software_version": "NRZ-2017-089", "sensordatavalues":[{"value_type":"SDS_P1","value":"7.87"}]}"#;
+        let source = StdinSource::new(json.as_bytes()).unwrap();
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+
+        // Every poll sees the same measurement instead of EOF
+        for _ in 0..2 {
+            let res = core.run(source.read_measurement(sensor.clone(), &core.handle()));
+            assert!(res.is_ok());
+        }
+    }
+
+    #[test]
+    fn create_client_invalid_ca_file() -> () {
+        let core = Core::new().unwrap();
+        let sensor = Sensor {
+            ca_file: Some("/does/not/exist.pem".to_string()),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+
+        match create_client(&core.handle(), &sensor) {
+            Err(Error(ErrorKind::InvalidCaFile(_), _)) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn classify_hyper_error_okay() -> () {
+        let refused = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+
+        match *classify_hyper_error(refused, "http://feinstaub".to_string()).kind() {
+            ErrorKind::ConnectionRefused(_) => assert!(true),
+            _ => assert!(false),
+        }
+        match *classify_hyper_error(hyper::Error::Timeout, "http://feinstaub".to_string()).kind() {
+            ErrorKind::Timeout(_) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn retry_transient_errors() -> () {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+
+        let res = core.run(retry(&core.handle(), 3, Duration::from_millis(1), Duration::from_millis(2), move || {
+            counter.set(counter.get() + 1);
+            if counter.get() < 3 {
+                Box::new(future::err(ErrorKind::HttpStatus(503, "http://feinstaub".to_string()).into()))
+            } else {
+                Box::new(future::ok(counter.get()))
+            }
+        }));
+
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn next_backoff_capped() -> () {
+        let max_backoff = Duration::from_secs(60);
+
+        assert_eq!(next_backoff(Duration::from_secs(20), max_backoff), Duration::from_secs(40));
+        assert_eq!(next_backoff(Duration::from_secs(40), max_backoff), max_backoff);
+        assert_eq!(next_backoff(max_backoff, max_backoff), max_backoff);
+    }
+
+    #[test]
+    fn retry_permanent_errors_once() -> () {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counter = attempts.clone();
+
+        let res: Result<()> = core.run(retry(&core.handle(), 3, Duration::from_millis(1), Duration::from_millis(2), move || {
+            counter.set(counter.get() + 1);
+            Box::new(future::err(ErrorKind::HttpStatus(404, "http://feinstaub".to_string()).into()))
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn fetch_body_times_out() -> () {
+        // The listener accepts connections, but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri: Uri = format!("http://{}/data.json", listener.local_addr().unwrap()).parse().unwrap();
+        let mut core = Core::new().unwrap();
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let client = create_client(&core.handle(), &sensor).unwrap();
+
+        let res = core.run(fetch_body(&client, &core.handle(), uri, Duration::from_millis(100), 0));
+
+        match res {
+            Err(Error(ErrorKind::Timeout(_), _)) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn is_followed_redirect_okay() -> () {
+        assert!(is_followed_redirect(301));
+        assert!(is_followed_redirect(308));
+        assert!(!is_followed_redirect(300));
+        assert!(!is_followed_redirect(304));
+        assert!(!is_followed_redirect(200));
+    }
+
+    #[test]
+    fn resolve_redirect_absolute_path() -> () {
+        let uri: Uri = "http://feinstaub:8080/data.json".parse().unwrap();
+
+        let res = resolve_redirect(&uri, "/v2/data.json");
+
+        assert_eq!(res.unwrap().to_string(), "http://feinstaub:8080/v2/data.json");
+    }
+
+    #[test]
+    fn resolve_redirect_absolute_uri() -> () {
+        let uri: Uri = "http://feinstaub/data.json".parse().unwrap();
+
+        let res = resolve_redirect(&uri, "http://proxy/feinstaub/data.json");
+
+        assert_eq!(res.unwrap().to_string(), "http://proxy/feinstaub/data.json");
+    }
+
+    #[test]
+    fn resolve_redirect_invalid() -> () {
+        let uri: Uri = "http://feinstaub/data.json".parse().unwrap();
+
+        let res = resolve_redirect(&uri, "login.html");
+
+        match res {
+            Err(Error(ErrorKind::InvalidRedirect(_, _), _)) => assert!(true),
+            _ => assert!(false),
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use sensor::SensorId;
+use check::{CheckedMeasurement, Direction, Severity, Violation, check_measurement, sensor_rules};
+use config::DEFAULT_INTERVAL;
+use history::{self, History};
+use measurement::{Measurement, Value};
+use sensor::{Sensor, SensorId};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::de::{self, Deserialize, Deserializer};
 use serde_json;
 use std::fs::File;
 use std::io::{Read, Write};
@@ -22,11 +29,114 @@ error_chain! {
 }
 
 
-#[derive(Debug, Deserialize, Serialize)]
-#[derive(PartialEq)]
+#[derive(Debug, Serialize)]
+#[derive(PartialEq, Clone, Copy)]
 pub enum AlarmState {
     Normal,
-    ThresholdExceeded,
+    Warning,
+    Alert,
+    Critical,
+    Unreachable,
+}
+
+impl AlarmState {
+    pub fn from_severity(severity: Option<Severity>) -> AlarmState {
+        match severity {
+            None => AlarmState::Normal,
+            Some(Severity::Warning) => AlarmState::Warning,
+            Some(Severity::Alert) => AlarmState::Alert,
+            Some(Severity::Critical) => AlarmState::Critical,
+        }
+    }
+
+    pub fn severity(&self) -> Option<Severity> {
+        match *self {
+            AlarmState::Warning => Some(Severity::Warning),
+            AlarmState::Alert => Some(Severity::Alert),
+            AlarmState::Critical => Some(Severity::Critical),
+            AlarmState::Normal | AlarmState::Unreachable => None,
+        }
+    }
+}
+
+const ALARM_STATES: &[&str] = &["Normal", "Warning", "Alert", "Critical", "Unreachable"];
+
+// State files written before severities have been introduced store exceeded thresholds as 'ThresholdExceeded'
+impl<'de> Deserialize<'de> for AlarmState {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<AlarmState, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        match &s[..] {
+            "Normal" => Ok(AlarmState::Normal),
+            "Warning" => Ok(AlarmState::Warning),
+            "Alert" | "ThresholdExceeded" => Ok(AlarmState::Alert),
+            "Critical" => Ok(AlarmState::Critical),
+            "Unreachable" => Ok(AlarmState::Unreachable),
+            _ => Err(de::Error::unknown_variant(&s, ALARM_STATES)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub value_type: String,
+    pub value: f32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct DailyMean {
+    pub date: NaiveDate,
+    pub sum: f32,
+    pub count: u32,
+    pub last_reading: DateTime<Utc>,
+}
+
+impl DailyMean {
+    pub fn mean(&self) -> f32 {
+        self.sum / self.count as f32
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct Exceedances {
+    pub year: i32,
+    pub days: u32,
+    pub days_with_data: u32,
+    pub limit: f32,
+}
+
+// Groups aggregate members polled at other times on their last reading; the values are not corrected for humidity
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct LastReading {
+    pub timestamp: DateTime<Utc>,
+    pub data_values: Vec<Value>,
+}
+
+// The clear margin only applies to the limit a rule violated last
+#[derive(Debug, Deserialize, Serialize)]
+#[derive(PartialEq, Clone)]
+pub struct ViolatedRule {
+    pub rule: String,
+    pub direction: Direction,
+    pub severity: Severity,
+    pub particulates: bool,
+}
+
+impl<'a> From<&'a Violation> for ViolatedRule {
+    fn from(violation: &'a Violation) -> ViolatedRule {
+        ViolatedRule {
+            rule: violation.rule_id.clone(),
+            direction: violation.direction,
+            severity: violation.severity,
+            particulates: violation.value.is_particulates(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -34,9 +144,315 @@ pub enum AlarmState {
 pub struct SensorState {
     pub sensor_id: SensorId,
     pub alarm_state: AlarmState,
+    #[serde(default)]
+    pub failed_readings: u32,
+    #[serde(default)]
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub first_failure: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub violated_rules: Vec<ViolatedRule>,
+    #[serde(default)]
+    pub pending_alarm_state: Option<AlarmState>,
+    #[serde(default)]
+    pub pending_readings: u32,
+    #[serde(default)]
+    pub samples: Vec<Sample>,
+    #[serde(skip)]
+    pub samples_from_history: Option<Vec<Sample>>,
+    #[serde(default)]
+    pub daily_means_pm10: Vec<DailyMean>,
+    #[serde(skip)]
+    pub daily_means_from_history: Option<Vec<DailyMean>>,
+    #[serde(default)]
+    pub last_particulates: Vec<f32>,
+    #[serde(default)]
+    pub identical_readings: u32,
+    #[serde(default)]
+    pub last_sample: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub suspect: bool,
+    #[serde(default)]
+    pub last_reading: Option<LastReading>,
 }
 
 impl SensorState {
+    pub fn new(sensor_id: &SensorId) -> SensorState {
+        SensorState {
+            sensor_id: sensor_id.clone(),
+            alarm_state: AlarmState::Normal,
+            failed_readings: 0,
+            last_success: None,
+            first_failure: None,
+            violated_rules: Vec::new(),
+            pending_alarm_state: None,
+            pending_readings: 0,
+            samples: Vec::new(),
+            samples_from_history: None,
+            daily_means_pm10: Vec::new(),
+            daily_means_from_history: None,
+            last_particulates: Vec::new(),
+            identical_readings: 0,
+            last_sample: None,
+            suspect: false,
+            last_reading: None,
+        }
+    }
+
+    pub fn record_measurement(&mut self, sensor: &Sensor, violations: &[Violation], timestamp: DateTime<Utc>) -> bool {
+        let violated_rules = violations.iter().map(ViolatedRule::from).collect();
+        self.record_violated_rules(sensor, violated_rules, timestamp)
+    }
+
+    // Particulates that are not checked due to high humidity neither raise nor clear alarms of particulates rules
+    pub fn record_humidity_suppressed_measurement(&mut self, sensor: &Sensor, violations: &[Violation], timestamp: DateTime<Utc>) -> bool {
+        let mut violated_rules = self.violated_rules.iter().filter(|r| r.particulates).cloned().collect::<Vec<_>>();
+        violated_rules.extend(violations.iter().map(ViolatedRule::from));
+        self.record_violated_rules(sensor, violated_rules, timestamp)
+    }
+
+    // The alarm state only changes once a new level holds for 'hold_readings' consecutive readings
+    fn record_violated_rules(&mut self, sensor: &Sensor, violated_rules: Vec<ViolatedRule>, timestamp: DateTime<Utc>) -> bool {
+        let recovered = self.alarm_state == AlarmState::Unreachable;
+
+        let alarm_state = AlarmState::from_severity(violated_rules.iter().map(|r| r.severity).max());
+        if recovered || alarm_state == self.alarm_state {
+            self.alarm_state = alarm_state;
+            self.pending_alarm_state = None;
+            self.pending_readings = 0;
+        } else {
+            if self.pending_alarm_state == Some(alarm_state) {
+                self.pending_readings += 1;
+            } else {
+                self.pending_alarm_state = Some(alarm_state);
+                self.pending_readings = 1;
+            }
+            if self.pending_readings >= sensor.hold_readings.unwrap_or(1) {
+                self.alarm_state = alarm_state;
+                self.pending_alarm_state = None;
+                self.pending_readings = 0;
+            }
+        }
+        self.violated_rules = violated_rules;
+        self.failed_readings = 0;
+        self.last_success = Some(timestamp);
+        self.first_failure = None;
+
+        recovered
+    }
+
+    pub fn violated_direction(&self, rule_id: &str) -> Option<Direction> {
+        self.violated_rules.iter().find(|r| r.rule == rule_id).map(|r| r.direction)
+    }
+
+    // Only values used by means, rates of change, or the NowCast are kept and only as long as the longest time window requires
+    pub fn record_samples(&mut self, measurement: &Measurement) {
+        if self.samples_from_history.is_some() {
+            return;
+        }
+        let timestamp = measurement.timestamp.unwrap_or(measurement.fetched_at);
+        let windows = sample_windows(&measurement.sensor);
+        for &(ref value_type, _) in &windows {
+            if self.samples.iter().any(|s| s.timestamp == timestamp && &s.value_type == value_type) {
+                continue;
+            }
+            if let Some(value) = measurement.data_values.iter().filter_map(|v| v.select(value_type)).next() {
+                self.samples.push(Sample {
+                    timestamp: timestamp,
+                    value_type: value_type.clone(),
+                    value: value,
+                });
+            }
+        }
+        self.samples.retain(|s| {
+            windows
+                .iter()
+                .filter(|&&(ref value_type, _)| value_type == &s.value_type)
+                .any(|&(_, window)| s.timestamp >= timestamp - window)
+        });
+    }
+
+    // The history replaces the samples kept in the state; the current measurement is not part of the samples.
+    // Returns the invalid lines skipped in the history.
+    pub fn load_samples(&mut self, history: &History, measurement: &Measurement) -> history::Result<Vec<history::Error>> {
+        let timestamp = measurement.timestamp.unwrap_or(measurement.fetched_at);
+        let windows = sample_windows(&measurement.sensor);
+        // Unwrap is safe because there is at least the window of the NowCast
+        let longest = windows.iter().map(|&(_, window)| window).max().unwrap();
+        let mut samples = Vec::new();
+        let (entries, skipped) = history.entries(&self.sensor_id, timestamp - longest, timestamp)?;
+        for e in entries {
+            for &(ref value_type, window) in &windows {
+                if e.timestamp < timestamp - window || samples.iter().any(|s: &Sample| s.timestamp == e.timestamp && &s.value_type == value_type) {
+                    continue;
+                }
+                if let Some(value) = e.select(value_type) {
+                    samples.push(Sample {
+                        timestamp: e.timestamp,
+                        value_type: value_type.clone(),
+                        value: value,
+                    });
+                }
+            }
+        }
+        self.samples.clear();
+        self.samples_from_history = Some(samples);
+
+        Ok(skipped)
+    }
+
+    // The reading is dated back by its age so that it ages the same way as a fresh reading
+    pub fn record_last_reading(&mut self, measurement: &Measurement, raw_data_values: &[Value]) {
+        let timestamp = measurement.fetched_at - measurement.reading_age().unwrap_or_else(Duration::zero);
+        self.last_reading = Some(LastReading { timestamp: timestamp, data_values: raw_data_values.to_vec() });
+    }
+
+    // The last reading is checked again without state so that its values are corrected like a fresh reading; it is left
+    // out once older than 'max_age' seconds, which defaults to two polling intervals of the sensor
+    pub fn last_checked_measurement(&self, sensor: &Sensor, max_age: Option<u64>, now: DateTime<Utc>) -> Option<CheckedMeasurement> {
+        let max_age = max_age.unwrap_or_else(|| 2 * sensor.interval.unwrap_or(DEFAULT_INTERVAL));
+        self.last_reading
+            .as_ref()
+            .map(|reading| {
+                check_measurement(Measurement {
+                    sensor: Sensor { max_age: Some(max_age), ..sensor.clone() },
+                    software_version: String::new(),
+                    data_values: reading.data_values.clone(),
+                    timestamp: Some(reading.timestamp),
+                    location: None,
+                    warnings: Vec::new(),
+                    fetched_at: now,
+                    age: None,
+                })
+            })
+            .filter(|cm| !cm.stale)
+    }
+
+    // Counts the consecutive samples with exactly the same particulates; polling faster than the sensor samples returns
+    // the same sample again, which is only counted once
+    pub fn record_particulates(&mut self, measurement: &Measurement, particulates: &[f32]) {
+        if self.is_same_sample(measurement) {
+            return;
+        }
+        self.last_sample = Some(measurement.sampled_at());
+        if !particulates.is_empty() && particulates == &self.last_particulates[..] {
+            self.identical_readings += 1;
+        } else {
+            self.last_particulates = particulates.to_vec();
+            self.identical_readings = 1;
+        }
+    }
+
+    // The number of consecutive samples with the same particulates including the current reading
+    pub fn count_identical_readings(&self, measurement: &Measurement, particulates: &[f32]) -> u32 {
+        if particulates.is_empty() || particulates != &self.last_particulates[..] {
+            0
+        } else if self.is_same_sample(measurement) {
+            self.identical_readings
+        } else {
+            self.identical_readings + 1
+        }
+    }
+
+    fn is_same_sample(&self, measurement: &Measurement) -> bool {
+        self.last_sample.map(|sampled_at| measurement.is_same_sample(sampled_at)).unwrap_or(false)
+    }
+
+    // Daily means of PM 10 are kept for the current and the previous calendar year unless they are derived from the history
+    pub fn record_daily_mean(&mut self, measurement: &Measurement) {
+        if self.daily_means_from_history.is_some() {
+            return;
+        }
+        let timestamp = measurement.timestamp.unwrap_or(measurement.fetched_at);
+        let pm10 = match measurement.data_values.iter().filter_map(|v| v.pm10()).next() {
+            Some(pm10) => pm10,
+            None => return,
+        };
+        let date = timestamp.naive_utc().date();
+        let first_date = NaiveDate::from_ymd(date.year() - 1, 1, 1);
+        self.daily_means_pm10.retain(|d| d.date >= first_date);
+
+        if let Some(daily_mean) = self.daily_means_pm10.iter_mut().find(|d| d.date == date) {
+            // The same reading may be read more than once, e.g., from the API
+            if timestamp > daily_mean.last_reading {
+                daily_mean.sum += pm10;
+                daily_mean.count += 1;
+                daily_mean.last_reading = timestamp;
+            }
+            return;
+        }
+        self.daily_means_pm10.push(DailyMean {
+            date: date,
+            sum: pm10,
+            count: 1,
+            last_reading: timestamp,
+        });
+    }
+
+    // A history that covers a whole year replaces the daily means kept in the state; returns the invalid lines skipped
+    // in the history
+    pub fn load_daily_means(&mut self, history: &History, year: i32) -> history::Result<Vec<history::Error>> {
+        let from = DateTime::from_utc(NaiveDate::from_ymd(year, 1, 1).and_hms(0, 0, 0), Utc);
+        let to = DateTime::from_utc(NaiveDate::from_ymd(year + 1, 1, 1).and_hms(0, 0, 0), Utc);
+        let (daily_means, skipped) = history.daily_means(&self.sensor_id, "pm10", from, to)?;
+        self.daily_means_pm10.clear();
+        self.daily_means_from_history = Some(daily_means);
+
+        Ok(skipped)
+    }
+
+    // Only complete days, i.e., days before 'today', are counted
+    pub fn exceedances(&self, year: i32, limit: f32, today: NaiveDate) -> Exceedances {
+        let days = self.daily_means_from_history
+            .as_ref()
+            .unwrap_or(&self.daily_means_pm10)
+            .iter()
+            .filter(|d| d.date.year() == year && d.date < today)
+            .collect::<Vec<_>>();
+
+        Exceedances {
+            year: year,
+            days: days.iter().filter(|d| d.mean() > limit).count() as u32,
+            days_with_data: days.len() as u32,
+            limit: limit,
+        }
+    }
+
+    // Samples from 'from' inclusive up to 'to' exclusive
+    pub fn samples(&self, value_type: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<&Sample> {
+        self.samples_from_history
+            .as_ref()
+            .unwrap_or(&self.samples)
+            .iter()
+            .filter(|s| s.value_type == value_type && s.timestamp >= from && s.timestamp < to)
+            .collect()
+    }
+
+    pub fn record_failure(&mut self, sensor: &Sensor, timestamp: DateTime<Utc>) -> bool {
+        self.failed_readings += 1;
+        if self.first_failure.is_none() {
+            self.first_failure = Some(timestamp);
+        }
+        if self.alarm_state == AlarmState::Unreachable {
+            return false;
+        }
+
+        let too_many_failures = sensor.unreachable_after_failures
+            .map(|max| self.failed_readings >= max)
+            .unwrap_or(false);
+        // A sensor that has never been read successfully is unreachable since its first failure
+        let too_long_without_success = match (sensor.unreachable_after, self.last_success.or(self.first_failure)) {
+            (Some(secs), Some(since)) => timestamp.signed_duration_since(since) >= Duration::seconds(secs as i64),
+            _ => false,
+        };
+        if too_many_failures || too_long_without_success {
+            self.alarm_state = AlarmState::Unreachable;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn load<P: AsRef<Path>>(sensor_id: &SensorId, state_dir: P) -> Result<SensorState> {
         let fp = create_filepath(sensor_id, state_dir.as_ref());
         load_from_file(fp).chain_err(|| ErrorKind::FailedToLoadState(sensor_id.clone(), state_dir.as_ref().to_string_lossy().to_string()))
@@ -48,6 +464,19 @@ impl SensorState {
     }
 }
 
+// Time windows of the means and rates of change per value type
+fn sample_windows(sensor: &Sensor) -> Vec<(String, Duration)> {
+    let mut windows = sensor_rules(sensor)
+        .into_iter()
+        .filter_map(|r| r.window().map(|window| (r.value.to_lowercase(), window)))
+        .collect::<Vec<_>>();
+    // The NowCast of the US EPA AQI requires the particulates of the last 12 hours
+    windows.push(("pm10".to_string(), Duration::hours(12)));
+    windows.push(("pm2".to_string(), Duration::hours(12)));
+
+    windows
+}
+
 fn create_filepath<P: AsRef<Path>>(sensor_id: &SensorId, state_dir: P) -> PathBuf {
     let mut pb = state_dir.as_ref().to_path_buf();
     let filename = format!("{}.json", sensor_id);
@@ -79,11 +508,38 @@ fn save_state_to_file<P: AsRef<Path>>(state: &SensorState, file_path: P) -> Resu
     #[cfg(test)]
 mod test {
     use super::*;
+    use check::Threshold;
+    use measurement::Value;
     use mktemp::Temp;
 
+    fn measurement(sensor: &Sensor, timestamp: DateTime<Utc>, value: f32) -> Measurement {
+        Measurement {
+            sensor: sensor.clone(),
+            software_version: "NRZ-2017-089".to_string(),
+            data_values: vec![Value::SDS_P1(value), Value::SDS_P2(value)],
+            timestamp: Some(timestamp),
+            location: None,
+            warnings: Vec::new(),
+            fetched_at: timestamp,
+            age: None,
+        }
+    }
+
+    fn violation(severity: Severity) -> Violation {
+        Violation {
+            rule: "threshold_pm10".to_string(),
+            rule_id: "threshold_pm10 (pm10 above 50)".to_string(),
+            value: Value::SDS_P1(57.87),
+            measured: 57.87,
+            limit: 50.0,
+            direction: Direction::Above,
+            severity: severity,
+        }
+    }
+
     #[test]
     pub fn save_state_to_file_ok() -> () {
-        let sensor_state = SensorState { sensor_id: "123456789".to_string() , alarm_state: AlarmState::Normal };
+        let sensor_state = SensorState::new(&"123456789".to_string());
 
         let file = Temp::new_file().unwrap().to_path_buf();
         let res = save_state_to_file(&sensor_state, file);
@@ -92,7 +548,7 @@ mod test {
 
     #[test]
     pub fn load_from_file_ok() -> () {
-        let expected_sensor_state = SensorState { sensor_id: "123456789".to_string() , alarm_state: AlarmState::ThresholdExceeded};
+        let expected_sensor_state = SensorState { alarm_state: AlarmState::Critical, ..SensorState::new(&"123456789".to_string()) };
         let file = Temp::new_file().unwrap().to_path_buf();
         let res = save_state_to_file(&expected_sensor_state,&file);
         assert!(res.is_ok());
@@ -101,4 +557,224 @@ mod test {
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), expected_sensor_state);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn load_from_legacy_file_ok() -> () {
+        let file = Temp::new_file().unwrap().to_path_buf();
+        {
+            let mut f = File::create(&file).unwrap();
+            f.write_all(br#"{"sensor_id":"123456789","alarm_state":"ThresholdExceeded"}"#).unwrap();
+        }
+
+        let res = load_from_file(&file);
+
+        let state = res.unwrap();
+        assert_eq!(state.alarm_state, AlarmState::Alert);
+        assert_eq!(state.failed_readings, 0);
+        assert_eq!(state.last_success, None);
+    }
+
+    #[test]
+    pub fn record_measurement_severity() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+
+        state.record_measurement(&sensor, &[violation(Severity::Warning), violation(Severity::Critical)], now);
+        assert_eq!(state.alarm_state, AlarmState::Critical);
+        assert_eq!(state.alarm_state.severity(), Some(Severity::Critical));
+        assert_eq!(state.violated_direction("threshold_pm10 (pm10 above 50)"), Some(Direction::Above));
+        assert_eq!(state.violated_rules.len(), 2);
+
+        state.record_measurement(&sensor, &[], now);
+        assert_eq!(state.alarm_state, AlarmState::Normal);
+        assert_eq!(state.alarm_state.severity(), None);
+        assert!(state.violated_rules.is_empty());
+    }
+
+    #[test]
+    pub fn record_measurement_hold_readings() -> () {
+        let sensor = Sensor {
+            hold_readings: Some(2),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+
+        state.record_measurement(&sensor, &[violation(Severity::Alert)], now);
+        assert_eq!(state.alarm_state, AlarmState::Normal);
+        state.record_measurement(&sensor, &[], now);
+        state.record_measurement(&sensor, &[violation(Severity::Alert)], now);
+        assert_eq!(state.alarm_state, AlarmState::Normal);
+        state.record_measurement(&sensor, &[violation(Severity::Alert)], now);
+        assert_eq!(state.alarm_state, AlarmState::Alert);
+        assert_eq!(state.pending_alarm_state, None);
+
+        state.record_measurement(&sensor, &[], now);
+        assert_eq!(state.alarm_state, AlarmState::Alert);
+        assert_eq!(state.pending_alarm_state, Some(AlarmState::Normal));
+    }
+
+    #[test]
+    pub fn record_samples_prunes_samples() -> () {
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Mean { mean: "24h".to_string(), above: 50.0 }),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+
+        state.record_samples(&measurement(&sensor, now - Duration::hours(25), 10.0));
+        state.record_samples(&measurement(&sensor, now - Duration::hours(1), 20.0));
+        state.record_samples(&measurement(&sensor, now - Duration::hours(1), 20.0));
+        assert_eq!(state.samples.len(), 3);
+        state.record_samples(&measurement(&sensor, now, 30.0));
+
+        assert_eq!(state.samples.len(), 4);
+        assert_eq!(state.samples("pm10", now - Duration::hours(24), now).len(), 1);
+        // PM 2.5 is only kept for the 12 hours of the NowCast
+        assert_eq!(state.samples("pm2", now - Duration::hours(24), now).len(), 1);
+    }
+
+    #[test]
+    pub fn load_samples_from_history() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor = Sensor {
+            threshold_pm10: Some(Threshold::Mean { mean: "24h".to_string(), above: 50.0 }),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+        for &hours in &[25, 13, 1] {
+            history.append(&measurement(&sensor, now - Duration::hours(hours), 10.0)).unwrap();
+        }
+        state.record_samples(&measurement(&sensor, now - Duration::hours(2), 20.0));
+
+        state.load_samples(&history, &measurement(&sensor, now, 30.0)).unwrap();
+        state.record_samples(&measurement(&sensor, now, 30.0));
+
+        assert!(state.samples.is_empty());
+        assert_eq!(state.samples("pm10", now - Duration::hours(24), now).len(), 2);
+        assert_eq!(state.samples("pm2", now - Duration::hours(24), now).len(), 1);
+    }
+
+    #[test]
+    pub fn exceedances_okay() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let mut state = SensorState::new(&sensor.id);
+
+        state.record_daily_mean(&measurement(&sensor, "2016-12-31T12:00:00Z".parse().unwrap(), 80.0));
+        state.record_daily_mean(&measurement(&sensor, "2017-12-31T12:00:00Z".parse().unwrap(), 80.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-01T06:00:00Z".parse().unwrap(), 80.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-01T12:00:00Z".parse().unwrap(), 30.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-01T12:00:00Z".parse().unwrap(), 30.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-02T06:00:00Z".parse().unwrap(), 40.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-03T06:00:00Z".parse().unwrap(), 60.0));
+        state.record_daily_mean(&measurement(&sensor, "2018-01-04T06:00:00Z".parse().unwrap(), 60.0));
+
+        assert_eq!(state.daily_means_pm10.len(), 5);
+        assert_eq!(state.daily_means_pm10[1].mean(), 55.0);
+        let exceedances = state.exceedances(2018, 50.0, NaiveDate::from_ymd(2018, 1, 4));
+        assert_eq!(exceedances.days, 2);
+        assert_eq!(exceedances.days_with_data, 3);
+        assert_eq!(state.exceedances(2017, 50.0, NaiveDate::from_ymd(2018, 1, 4)).days, 1);
+    }
+
+    #[test]
+    pub fn exceedances_from_history() -> () {
+        let state_dir = Temp::new_dir().unwrap();
+        let history = History::new(state_dir.to_path_buf());
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let mut state = SensorState::new(&sensor.id);
+        for &(timestamp, value) in &[("2017-12-31T12:00:00Z", 80.0), ("2018-01-01T06:00:00Z", 80.0), ("2018-01-01T12:00:00Z", 30.0), ("2018-01-02T06:00:00Z", 60.0)] {
+            history.append(&measurement(&sensor, timestamp.parse().unwrap(), value)).unwrap();
+        }
+        state.record_daily_mean(&measurement(&sensor, "2018-01-03T06:00:00Z".parse().unwrap(), 90.0));
+
+        state.load_daily_means(&history, 2018).unwrap();
+        // The history replaces the daily means kept in the state
+        state.record_daily_mean(&measurement(&sensor, "2018-01-03T06:00:00Z".parse().unwrap(), 90.0));
+        let exceedances = state.exceedances(2018, 50.0, NaiveDate::from_ymd(2018, 1, 4));
+
+        assert!(state.daily_means_pm10.is_empty());
+        assert_eq!(exceedances.days, 2);
+        assert_eq!(exceedances.days_with_data, 2);
+    }
+
+    #[test]
+    pub fn record_humidity_suppressed_measurement_okay() -> () {
+        let sensor = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost");
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+        let frost = Violation {
+            rule: "frost".to_string(),
+            rule_id: "frost (temperature below -5)".to_string(),
+            value: Value::TEMPERATURE(-7.0),
+            measured: -7.0,
+            limit: -5.0,
+            direction: Direction::Below,
+            severity: Severity::Warning,
+        };
+        state.record_measurement(&sensor, &[violation(Severity::Alert), frost], now);
+        assert_eq!(state.alarm_state, AlarmState::Alert);
+
+        // A humid reading neither clears the particulates alarm nor keeps the other rules violated
+        state.record_humidity_suppressed_measurement(&sensor, &[], now);
+        assert_eq!(state.alarm_state, AlarmState::Alert);
+        assert_eq!(state.violated_direction("threshold_pm10 (pm10 above 50)"), Some(Direction::Above));
+        assert_eq!(state.violated_direction("frost (temperature below -5)"), None);
+
+        state.record_measurement(&sensor, &[], now);
+        assert_eq!(state.alarm_state, AlarmState::Normal);
+    }
+
+    #[test]
+    pub fn record_failure_unreachable_after_failures() -> () {
+        let sensor = Sensor {
+            unreachable_after_failures: Some(2),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+
+        assert!(!state.record_failure(&sensor, now));
+        assert!(state.record_failure(&sensor, now));
+        assert_eq!(state.alarm_state, AlarmState::Unreachable);
+        assert!(!state.record_failure(&sensor, now));
+        assert_eq!(state.failed_readings, 3);
+
+        assert!(state.record_measurement(&sensor, &[], now));
+        assert_eq!(state.alarm_state, AlarmState::Normal);
+        assert_eq!(state.failed_readings, 0);
+    }
+
+    #[test]
+    pub fn record_failure_unreachable_after_duration() -> () {
+        let sensor = Sensor {
+            unreachable_after: Some(600),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+        state.record_measurement(&sensor, &[violation(Severity::Warning)], now);
+
+        assert!(!state.record_failure(&sensor, now + Duration::seconds(300)));
+        assert!(state.record_failure(&sensor, now + Duration::seconds(600)));
+        assert_eq!(state.alarm_state, AlarmState::Unreachable);
+    }
+
+    #[test]
+    pub fn record_failure_unreachable_without_success() -> () {
+        let sensor = Sensor {
+            unreachable_after: Some(600),
+            ..Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost")
+        };
+        let mut state = SensorState::new(&sensor.id);
+        let now = Utc::now();
+
+        assert!(!state.record_failure(&sensor, now));
+        assert!(!state.record_failure(&sensor, now + Duration::seconds(300)));
+        assert!(state.record_failure(&sensor, now + Duration::seconds(600)));
+    }
+}
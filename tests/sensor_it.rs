@@ -10,9 +10,9 @@ use tokio_core::reactor::Core;
 fn read_measurement_local() -> () {
     let mut core = Core::new().unwrap();
 
-    let uri = "http://feinstaub/data.json".parse().unwrap();
-    let response = create_sensor_reader(&mut core).get(uri);
-    let work = Sensor::new("A Sensor", "123456789", "http://localhost", "http://localhost").read_measurement(response);
+    let sensor = Sensor::new("A Sensor", "123456789", "http://feinstaub", "http://feinstaub/data.json");
+    let source = create_sensor_reader(&core.handle(), &sensor).unwrap();
+    let work = source.read_measurement(sensor, &core.handle());
     let res = core.run(work).unwrap();
 
     assert_eq!(res.data_values.len(), 8);